@@ -0,0 +1,100 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{energy, map_protocol::high_level::MapInfo};
+
+/// Payload of the retained `<prefix>/total` message: power summed over the
+/// MAPs, kWh over the energy totals of every MAP
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Totals {
+    /// How many MAPs reported within the stale timeout
+    pub units: usize,
+    pub p_load: u32,
+    pub p_net: u32,
+    pub battery_current: f32,
+    /// Set by the owner of the energy ledger, see [`crate::energy::Ledger::totals`]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub energy: BTreeMap<&'static str, energy::Totals>,
+}
+
+/// Latest sample of every MAP, keyed by unit id. A MAP that has not reported
+/// for `stale_after` is left out until it reports again.
+#[derive(Debug)]
+pub struct Aggregate {
+    stale_after: Duration,
+    latest: BTreeMap<String, MapInfo>,
+    seen: BTreeMap<String, Instant>,
+}
+
+impl Aggregate {
+    pub fn new(stale_after: Duration) -> Self {
+        Self {
+            stale_after,
+            latest: BTreeMap::new(),
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, id: String, map_info: MapInfo, now: Instant) {
+        self.seen.insert(id.clone(), now);
+        self.latest.insert(id, map_info);
+        self.expire(now);
+    }
+
+    /// Drops the MAPs that stopped reporting
+    pub fn expire(&mut self, now: Instant) {
+        let stale_after = self.stale_after;
+        self.seen
+            .retain(|_, seen| now.saturating_duration_since(*seen) < stale_after);
+        self.latest.retain(|id, _| self.seen.contains_key(id));
+    }
+
+    pub fn latest(&self) -> &BTreeMap<String, MapInfo> {
+        &self.latest
+    }
+
+    pub fn totals(&self) -> Totals {
+        self.latest
+            .values()
+            .fold(Totals::default(), |totals, m| Totals {
+                units: totals.units + 1,
+                p_load: totals.p_load + m.p_load,
+                p_net: totals.p_net + m.p_net,
+                battery_current: totals.battery_current + m.battery_current,
+                ..totals
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_latest_sample_of_each_unit() {
+        let mut aggregate = Aggregate::new(Duration::from_secs(300));
+        let sample = |p_load| MapInfo {
+            p_load,
+            ..Default::default()
+        };
+        let t0 = Instant::now();
+        aggregate.update("1".into(), sample(100), t0);
+        aggregate.update("2".into(), sample(200), t0);
+        aggregate.update("1".into(), sample(150), t0 + Duration::from_secs(10));
+        let totals = aggregate.totals();
+        assert_eq!(totals.units, 2);
+        assert_eq!(totals.p_load, 350);
+
+        // unit 2 stopped answering
+        aggregate.update("1".into(), sample(150), t0 + Duration::from_secs(300));
+        let totals = aggregate.totals();
+        assert_eq!(totals.units, 1);
+        assert_eq!(totals.p_load, 150);
+        aggregate.expire(t0 + Duration::from_secs(600));
+        assert!(aggregate.latest().is_empty());
+    }
+}
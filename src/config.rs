@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+use duration_human::DurationHuman;
+use serde::{Deserialize, Deserializer};
+
+use crate::{notify::NotifyConfig, publish_policy::FieldDeadband, tariff::Tariff};
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+
+/// Settings read from the `--config` TOML file. They override the command line
+/// and are read again on SIGHUP.
+///
+/// ```toml
+/// interval = "10s"
+/// min_publish_interval = "30s"
+/// max_publish_interval = "5min"
+///
+/// [deadbands]
+/// u_acc = 0.5
+/// p_load = "10%"
+/// ```
+///
+/// `[tariff]` is described in [`Tariff`], `[notify]` in [`crate::notify`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub interval: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub min_publish_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_publish_interval: Option<Duration>,
+    #[serde(default)]
+    deadbands: BTreeMap<String, DeadbandValue>,
+    #[serde(default)]
+    pub tariff: Option<Tariff>,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DeadbandValue {
+    Number(f64),
+    Text(String),
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        let config: Self = toml::from_str(&text)
+            .with_context(|| format!("cannot parse config file {}", path.display()))?;
+        config
+            .check()
+            .with_context(|| format!("bad config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// The durations must be within the limits of the command line flags
+    fn check(&self) -> anyhow::Result<()> {
+        let limits = [
+            ("interval", self.interval, 10 * MINUTE),
+            ("min_publish_interval", self.min_publish_interval, HOUR),
+            ("max_publish_interval", self.max_publish_interval, 24 * HOUR),
+        ];
+        for (name, value, max) in limits {
+            let range = Duration::from_secs(1)..=Duration::from_secs(max);
+            if let Some(value) = value.filter(|value| !range.contains(value)) {
+                bail!(
+                    "`{name}` of {value:?} is outside {:?}..={:?}",
+                    range.start(),
+                    range.end()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn deadbands(&self) -> anyhow::Result<Vec<FieldDeadband>> {
+        self.deadbands
+            .iter()
+            .map(|(field, value)| match value {
+                DeadbandValue::Number(value) => format!("{field}={value}").parse(),
+                DeadbandValue::Text(value) => format!("{field}={value}").parse(),
+            })
+            .collect()
+    }
+}
+
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    DurationHuman::parse(&value)
+        .map(|duration| Some(Duration::from(&duration)))
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publish_policy::Deadband;
+
+    #[test]
+    fn parses_config_file() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            interval = "20s"
+            max_publish_interval = "5min"
+
+            [deadbands]
+            u_acc = 0.5
+            p_load = "10%"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.interval, Some(Duration::from_secs(20)));
+        assert_eq!(config.min_publish_interval, None);
+        assert_eq!(config.max_publish_interval, Some(Duration::from_secs(300)));
+        let deadbands = config.deadbands().unwrap();
+        assert_eq!(deadbands[0].field, "p_load");
+        assert_eq!(deadbands[0].deadband, Deadband::Percent(10.0));
+        assert_eq!(deadbands[1].deadband, Deadband::Absolute(0.5));
+        config.check().unwrap();
+
+        let config: ConfigFile = toml::from_str(r#"interval = "0s""#).unwrap();
+        assert!(config.check().is_err());
+        let config: ConfigFile = toml::from_str(r#"max_publish_interval = "2days""#).unwrap();
+        assert!(config.check().is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Energy totals in kWh from the raw `e_net`, `e_acc` and `e_acc_charge` counters.
+//!
+//! The counters are 24 bits wide and go back to zero at midnight, when mapd
+//! used to set the clock of the MAP, or when they overflow. The bridge adds up
+//! the increments between polls instead of trusting the counters themselves:
+//!
+//! * a counter that grew adds the difference
+//! * a counter that fell from the top quarter of its range into the bottom one
+//!   wrapped and adds the distance across the wrap
+//! * any other fall is a reset and adds the new value, counted since the reset
+//!
+//! An increment more than [`MAX_POWER_W`] could deliver since the previous
+//! poll is a glitch of the serial line, it is logged and skipped and the
+//! counter is followed from its new value.
+//!
+//! mapd does not say how much energy one counter step is, so nothing is
+//! counted until `--energy-counter-wh` is given, checked against the display
+//! of the MAP.
+//!
+//! Days and months follow the local clock of the bridge. The totals are saved
+//! to `--energy-state`, see [`crate::state`], and survive restarts.
+
+use std::{collections::BTreeMap, path::Path, time::Instant};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    map_protocol::high_level::MapInfo,
+    state::StateFile,
+    tariff::{CostMeter, DailyCost, Tariff},
+};
+
+const COUNTER_RANGE: u32 = 1 << 24;
+
+/// Above the rating of the largest MAP, bounds the energy counted between polls
+pub const MAX_POWER_W: f64 = 30_000.0;
+
+/// kWh of one quantity, the payload of `<topic>/energy` has one per counter
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Totals {
+    pub today: f64,
+    pub yesterday: f64,
+    pub this_month: f64,
+    pub lifetime: f64,
+}
+
+/// Running totals of one counter
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meter {
+    /// Raw counter value of the previous poll
+    last_counter: Option<u32>,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+impl Meter {
+    /// Adds the increment since the previous poll, `max_wh` bounds it when the
+    /// time of the previous poll is known
+    fn add(&mut self, counter: u32, wh_per_count: f64, max_wh: Option<f64>) {
+        let increment = match self.last_counter {
+            None => 0,
+            Some(last) if counter >= last => counter - last,
+            Some(last) if last >= COUNTER_RANGE / 4 * 3 && counter < COUNTER_RANGE / 4 => {
+                counter + COUNTER_RANGE - last
+            }
+            Some(_) => counter,
+        };
+        self.last_counter = Some(counter);
+        let wh = f64::from(increment) * wh_per_count;
+        if max_wh.is_some_and(|max_wh| wh > max_wh) {
+            warn!(
+                "energy counter went to {counter}, {wh} Wh is implausible since the previous poll, skipped"
+            );
+            return;
+        }
+        let kwh = wh / 1000.0;
+        self.totals.today += kwh;
+        self.totals.this_month += kwh;
+        self.totals.lifetime += kwh;
+    }
+
+    fn new_day(&mut self, previous: NaiveDate, today: NaiveDate) {
+        self.totals.yesterday = if previous.succ_opt() == Some(today) {
+            self.totals.today
+        } else {
+            0.0
+        };
+        self.totals.today = 0.0;
+        if (previous.year(), previous.month()) != (today.year(), today.month()) {
+            self.totals.this_month = 0.0;
+        }
+    }
+}
+
+/// Energy accounting of one MAP
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Energy {
+    /// Day of the last update
+    day: Option<NaiveDate>,
+    /// Time of the last update, missing in files saved by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated: Option<DateTime<Local>>,
+    /// `e_net`
+    pub grid_import: Meter,
+    /// `e_acc`
+    pub battery_discharge: Meter,
+    /// `e_acc_charge`
+    pub battery_charge: Meter,
+}
+
+impl Energy {
+    pub fn update(&mut self, sample: &MapInfo, now: DateTime<Local>, wh_per_count: f64) {
+        let today = now.date_naive();
+        let previous = self.day.filter(|day| *day != today);
+        let max_wh = self.updated.map(|updated| {
+            let hours = (now - updated).num_milliseconds().max(0) as f64 / 3_600_000.0;
+            MAX_POWER_W * hours
+        });
+        let meters = [
+            (&mut self.grid_import, sample.e_net),
+            (&mut self.battery_discharge, sample.e_acc),
+            (&mut self.battery_charge, sample.e_acc_charge),
+        ];
+        for (meter, counter) in meters {
+            if let Some(previous) = previous {
+                meter.new_day(previous, today);
+            }
+            meter.add(counter, wh_per_count, max_wh);
+        }
+        self.day = Some(today);
+        self.updated = Some(now);
+    }
+
+    /// Payload of `<topic>/energy`
+    pub fn report(&self) -> BTreeMap<&'static str, Totals> {
+        BTreeMap::from([
+            ("grid_import", self.grid_import.totals),
+            ("battery_discharge", self.battery_discharge.totals),
+            ("battery_charge", self.battery_charge.totals),
+        ])
+    }
+}
+
+/// What the ledger keeps of one MAP
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Account {
+    #[serde(flatten)]
+    energy: Energy,
+    /// Cost of the day, with a tariff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cost: Option<CostMeter>,
+}
+
+/// Energy and cost accounting of every MAP, saved to a JSON file when one is given
+#[derive(Debug, Default)]
+pub struct Ledger {
+    state: Option<StateFile>,
+    /// Energy of one counter step, energy is not counted without it
+    wh_per_count: Option<f64>,
+    units: BTreeMap<String, Account>,
+}
+
+impl Ledger {
+    /// Reads the totals saved at `path`, a missing file starts from zero
+    pub fn open(path: Option<&Path>, wh_per_count: Option<f64>) -> anyhow::Result<Self> {
+        let state = path.map(|path| StateFile::new(path, "energy state"));
+        let units = match &state {
+            Some(state) => state.load()?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            state,
+            wh_per_count,
+            units,
+        })
+    }
+
+    /// Adds a sample and saves the changed totals, returns the energy of the
+    /// unit when the energy of a counter step is known
+    pub fn update(&mut self, id: &str, sample: &MapInfo, now: DateTime<Local>) -> Option<&Energy> {
+        let wh_per_count = self.wh_per_count?;
+        let energy = &mut self.units.entry(id.to_string()).or_default().energy;
+        let before = energy.clone();
+        energy.update(sample, now, wh_per_count);
+        if *energy != before {
+            self.changed();
+        }
+        Some(&self.units[id].energy)
+    }
+
+    /// Totals of every unit added up, for the aggregated topic
+    pub fn totals(&self) -> BTreeMap<&'static str, Totals> {
+        let mut sums: BTreeMap<&'static str, Totals> = BTreeMap::new();
+        if self.wh_per_count.is_none() {
+            return sums;
+        }
+        for account in self.units.values() {
+            for (quantity, totals) in account.energy.report() {
+                let sum = sums.entry(quantity).or_default();
+                sum.today += totals.today;
+                sum.yesterday += totals.yesterday;
+                sum.this_month += totals.this_month;
+                sum.lifetime += totals.lifetime;
+            }
+        }
+        sums
+    }
+
+    /// Adds a sample to the cost of the day and saves it, returns the cost of the unit
+    pub fn cost(
+        &mut self,
+        id: &str,
+        tariff: &Tariff,
+        sample: &MapInfo,
+        now: DateTime<Local>,
+    ) -> &DailyCost {
+        self.units
+            .entry(id.to_string())
+            .or_default()
+            .cost
+            .get_or_insert_with(CostMeter::default)
+            .update(tariff, sample, now);
+        self.changed();
+        self.units[id].cost.as_ref().unwrap().daily()
+    }
+
+    fn changed(&mut self) {
+        if let Some(state) = &mut self.state {
+            state.changed(&self.units, Instant::now());
+        }
+    }
+
+    /// Saves totals not saved yet, on shutdown
+    pub fn flush(&mut self) {
+        if let Some(state) = &mut self.state {
+            state.flush(&self.units);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn sample(e_net: u32) -> MapInfo {
+        MapInfo {
+            e_net,
+            ..Default::default()
+        }
+    }
+
+    fn assert_kwh(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} kWh, expected {expected}"
+        );
+    }
+
+    /// Noon plus `minutes` of a day of 2024
+    fn at(month: u32, day: u32, minutes: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, month, day, 12, 0, 0).unwrap()
+            + chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn counts_increments_across_wraps_and_resets() {
+        let mut energy = Energy::default();
+        for (minute, counter) in [(0, 1000), (1, 1300), (2, 200), (3, 500)] {
+            energy.update(&sample(counter), at(5, 1, minute), 1.0);
+        }
+        // 300, then a reset to 200, then 300 more
+        assert_kwh(energy.grid_import.totals.today, 0.8);
+        // a minute at 30 kW is 500 Wh, the jump is skipped and followed
+        energy.update(&sample(COUNTER_RANGE - 100), at(5, 1, 4), 1.0);
+        assert_kwh(energy.grid_import.totals.lifetime, 0.8);
+        energy.update(&sample(300), at(5, 1, 5), 1.0);
+        assert_kwh(energy.grid_import.totals.lifetime, 1.2);
+        // a long pause allows more
+        energy.update(&sample(40_300), at(5, 1, 125), 1.0);
+        assert_kwh(energy.grid_import.totals.lifetime, 41.2);
+    }
+
+    #[test]
+    fn reads_totals_saved_without_cost() {
+        let mut energy = Energy::default();
+        energy.update(&sample(5), at(5, 1, 0), 1.0);
+        let saved = serde_json::to_string(&BTreeMap::from([("1", &energy)])).unwrap();
+        let units: BTreeMap<String, Account> = serde_json::from_str(&saved).unwrap();
+        assert_eq!(units["1"].energy, energy);
+        assert!(units["1"].cost.is_none());
+    }
+
+    #[test]
+    fn adds_up_units_only_with_a_counter_scale() {
+        let mut ledger = Ledger::open(None, Some(1.0)).unwrap();
+        for id in ["1", "2"] {
+            ledger.update(id, &sample(0), at(5, 1, 0));
+            ledger.update(id, &sample(250), at(5, 1, 1));
+        }
+        assert_kwh(ledger.totals()["grid_import"].today, 0.5);
+        assert_kwh(ledger.totals()["battery_charge"].lifetime, 0.0);
+
+        let mut ledger = Ledger::open(None, None).unwrap();
+        assert!(ledger.update("1", &sample(0), at(5, 1, 0)).is_none());
+        assert!(ledger.totals().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_daily_cost_across_restarts() {
+        let tariff: Tariff =
+            toml::from_str("[[period]]\nfrom = \"00:00\"\nto = \"00:00\"\nimport = 6.0").unwrap();
+        let path = std::env::temp_dir().join(format!("map-energy-{}.json", std::process::id()));
+        let now = Local::now();
+        let mut ledger = Ledger::open(Some(&path), Some(1.0)).unwrap();
+        ledger.cost("1", &tariff, &sample(0), now);
+        let cost = ledger
+            .cost("1", &tariff, &sample(0), now + chrono::Duration::minutes(1))
+            .clone();
+        ledger.flush();
+
+        let mut ledger = Ledger::open(Some(&path), Some(1.0)).unwrap();
+        assert_eq!(ledger.units["1"].cost.as_ref().unwrap().daily(), &cost);
+        let later = now + chrono::Duration::minutes(2);
+        assert_eq!(ledger.cost("1", &tariff, &sample(0), later).date, cost.date);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rolls_days_and_months_over() {
+        let mut energy = Energy::default();
+        energy.update(&sample(0), at(5, 30, 0), 10.0);
+        energy.update(&sample(100), at(5, 30, 60), 10.0);
+        energy.update(&sample(150), at(5, 31, 0), 10.0);
+        let totals = energy.grid_import.totals;
+        assert_kwh(totals.yesterday, 1.0);
+        assert_kwh(totals.today, 0.5);
+        assert_kwh(totals.this_month, 1.5);
+        energy.update(&sample(150), at(6, 1, 0), 10.0);
+        let totals = energy.grid_import.totals;
+        assert_kwh(totals.yesterday, 0.5);
+        assert_kwh(totals.today, 0.0);
+        assert_kwh(totals.this_month, 0.0);
+        assert_kwh(totals.lifetime, 1.5);
+        energy.update(&sample(150), at(6, 5, 0), 10.0);
+        assert_eq!(energy.grid_import.totals.yesterday, 0.0);
+    }
+}
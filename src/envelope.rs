@@ -0,0 +1,78 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+
+/// Sample published to `<topic>` with the time and circumstances of the poll.
+/// The fields of the sample stay at the top level next to these.
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a, T: Serialize> {
+    /// Wall-clock time the poll started, RFC 3339
+    pub timestamp: String,
+    /// The same in milliseconds since the Unix epoch
+    pub timestamp_ms: i64,
+    /// Number of the poll since the bridge started, counting failed ones. Polls
+    /// that were not published leave gaps
+    pub seq: u64,
+    /// Time the MAP took to answer
+    pub poll_duration_ms: u64,
+    pub bridge_version: &'static str,
+    pub map: MapIdentity<'a>,
+    #[serde(flatten)]
+    pub sample: &'a T,
+}
+
+/// Which MAP the sample came from, its firmware is in the sample itself
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MapIdentity<'a> {
+    pub unit: &'a str,
+    pub port: &'a str,
+}
+
+impl<'a, T: Serialize> Envelope<'a, T> {
+    pub fn new(
+        sample: &'a T,
+        map: MapIdentity<'a>,
+        seq: u64,
+        started: DateTime<Local>,
+        poll_duration: std::time::Duration,
+    ) -> Self {
+        Self {
+            timestamp: started.to_rfc3339_opts(SecondsFormat::Millis, false),
+            timestamp_ms: started.timestamp_millis(),
+            seq,
+            poll_duration_ms: poll_duration.as_millis() as u64,
+            bridge_version: env!("CARGO_PKG_VERSION"),
+            map,
+            sample,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_protocol::high_level::MapInfo;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    #[test]
+    fn sample_fields_stay_at_the_top_level() {
+        let sample = MapInfo {
+            u_acc: 26.5,
+            ..Default::default()
+        };
+        let started = Local.timestamp_millis_opt(1_714_564_800_123).unwrap();
+        let identity = MapIdentity {
+            unit: "1",
+            port: "/dev/ttyUSB0",
+        };
+        let envelope = Envelope::new(&sample, identity, 7, started, Duration::from_millis(180));
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["u_acc"], 26.5);
+        assert_eq!(json["timestamp_ms"], 1_714_564_800_123i64);
+        assert!(json["timestamp"].as_str().unwrap().contains(".123"));
+        assert_eq!(json["seq"], 7);
+        assert_eq!(json["poll_duration_ms"], 180);
+        assert_eq!(json["map"]["port"], "/dev/ttyUSB0");
+        assert_eq!(json["bridge_version"], env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,270 @@
+//! Events derived from successive samples of a MAP.
+//!
+//! The detector follows a few on/off states and emits an event whenever one of
+//! them changes, with the time the previous state lasted:
+//!
+//! * grid - `u_net` is not zero, or the MAP is off with grid, translating or
+//!   charging (modes 1, 3 and 4)
+//! * charging - mode 4
+//! * generation - mode 2, the MAP feeds the load from the battery
+//! * relays 1 and 2
+//! * battery and grid overload flags
+//!
+//! The states are not saved: the first sample after a start of the bridge only
+//! sets them, so a restart does not report a grid loss. An outage already
+//! going on at the restart is reported when the grid comes back, with its
+//! duration counted from the restart.
+
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::Context;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+
+use crate::map_protocol::high_level::MapInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    GridLost,
+    GridRestored,
+    ChargingStarted,
+    ChargingStopped,
+    GenerationStarted,
+    GenerationStopped,
+    RelayOn,
+    RelayOff,
+    BatteryOverload,
+    BatteryOverloadCleared,
+    GridOverload,
+    GridOverloadCleared,
+}
+
+/// Payload of a `<topic>/events` message and a line of `--event-log`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    /// RFC 3339
+    pub timestamp: String,
+    pub unit: String,
+    pub kind: EventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<u8>,
+    /// How long the state that ended lasted, e.g. the outage for `grid_restored`
+    pub duration_s: i64,
+    /// Mode after the change
+    pub mode: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Grid,
+    Charging,
+    Generation,
+    Relay(u8),
+    BatteryOverload,
+    GridOverload,
+}
+
+impl State {
+    const ALL: [State; 7] = [
+        State::Grid,
+        State::Charging,
+        State::Generation,
+        State::Relay(1),
+        State::Relay(2),
+        State::BatteryOverload,
+        State::GridOverload,
+    ];
+
+    fn of(self, m: &MapInfo) -> bool {
+        match self {
+            State::Grid => m.u_net > 0 || matches!(m.mode_raw, 1 | 3 | 4),
+            State::Charging => m.mode_raw == 4,
+            State::Generation => m.mode_raw == 2,
+            State::Relay(1) => m.relay1 != 0,
+            State::Relay(_) => m.relay2 != 0,
+            State::BatteryOverload => m.f_acc_over != 0,
+            State::GridOverload => m.f_net_over != 0,
+        }
+    }
+
+    /// Event of the change to `on`
+    fn event(self, on: bool) -> EventKind {
+        use EventKind::*;
+        match (self, on) {
+            (State::Grid, true) => GridRestored,
+            (State::Grid, false) => GridLost,
+            (State::Charging, true) => ChargingStarted,
+            (State::Charging, false) => ChargingStopped,
+            (State::Generation, true) => GenerationStarted,
+            (State::Generation, false) => GenerationStopped,
+            (State::Relay(_), true) => RelayOn,
+            (State::Relay(_), false) => RelayOff,
+            (State::BatteryOverload, true) => BatteryOverload,
+            (State::BatteryOverload, false) => BatteryOverloadCleared,
+            (State::GridOverload, true) => GridOverload,
+            (State::GridOverload, false) => GridOverloadCleared,
+        }
+    }
+}
+
+/// Follows the states of one MAP
+#[derive(Debug)]
+pub struct EventDetector {
+    unit: String,
+    /// Every state with the time it was entered, empty before the first sample
+    states: Vec<(State, bool, DateTime<Local>)>,
+}
+
+impl EventDetector {
+    pub fn new(unit: String) -> Self {
+        Self {
+            unit,
+            states: Vec::new(),
+        }
+    }
+
+    /// Events of the changes since the previous sample
+    pub fn update(&mut self, sample: &MapInfo, now: DateTime<Local>) -> Vec<Event> {
+        if self.states.is_empty() {
+            self.states = State::ALL
+                .into_iter()
+                .map(|state| (state, state.of(sample), now))
+                .collect();
+            return Vec::new();
+        }
+        let mut events = Vec::new();
+        for (state, on, since) in &mut self.states {
+            let current = state.of(sample);
+            if current == *on {
+                continue;
+            }
+            events.push(Event {
+                timestamp: now.to_rfc3339_opts(SecondsFormat::Secs, false),
+                unit: self.unit.clone(),
+                kind: state.event(current),
+                relay: match state {
+                    State::Relay(number) => Some(*number),
+                    _ => None,
+                },
+                duration_s: (now - *since).num_seconds(),
+                mode: sample.mode.label(),
+            });
+            *on = current;
+            *since = now;
+        }
+        events
+    }
+}
+
+/// Appends events to the JSON Lines file at `path`
+pub fn append(path: &Path, events: &[Event]) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open event log {}", path.display()))?;
+    let mut lines = Vec::new();
+    for event in events {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+    file.write_all(&lines)
+        .with_context(|| format!("cannot write event log {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn reports_outage_with_its_duration() {
+        let mut detector = EventDetector::new("1".into());
+        let at = |minute| Local.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let grid = MapInfo {
+            mode_raw: 3,
+            u_net: 230,
+            ..Default::default()
+        };
+        let outage = MapInfo {
+            mode_raw: 2,
+            relay2: 2,
+            ..Default::default()
+        };
+        assert!(detector.update(&grid, at(0)).is_empty());
+        let events = detector.update(&outage, at(5));
+        let kinds: Vec<EventKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                EventKind::GridLost,
+                EventKind::GenerationStarted,
+                EventKind::RelayOn
+            ]
+        );
+        assert_eq!(events[2].relay, Some(2));
+        assert_eq!(events[0].duration_s, 300);
+
+        let events = detector.update(&grid, at(35));
+        assert_eq!(events[0].kind, EventKind::GridRestored);
+        assert_eq!(events[0].duration_s, 1800);
+        assert!(detector.update(&grid, at(36)).is_empty());
+    }
+
+    #[test]
+    fn reports_relays_and_overloads() {
+        let mut detector = EventDetector::new("1".into());
+        let at = |second| Local.with_ymd_and_hms(2024, 5, 1, 12, 0, second).unwrap();
+        let idle = MapInfo::default();
+        assert!(detector.update(&idle, at(0)).is_empty());
+
+        let events = detector.update(
+            &MapInfo {
+                relay1: 1,
+                f_acc_over: 1,
+                ..Default::default()
+            },
+            at(10),
+        );
+        let kinds: Vec<(EventKind, Option<u8>)> = events
+            .iter()
+            .map(|event| (event.kind, event.relay))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (EventKind::RelayOn, Some(1)),
+                (EventKind::BatteryOverload, None)
+            ]
+        );
+
+        let events = detector.update(
+            &MapInfo {
+                f_net_over: 1,
+                ..Default::default()
+            },
+            at(25),
+        );
+        let kinds: Vec<(EventKind, Option<u8>)> = events
+            .iter()
+            .map(|event| (event.kind, event.relay))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (EventKind::RelayOff, Some(1)),
+                (EventKind::BatteryOverloadCleared, None),
+                (EventKind::GridOverload, None)
+            ]
+        );
+        // the flag was clear since the first sample
+        let durations: Vec<i64> = events.iter().map(|event| event.duration_s).collect();
+        assert_eq!(durations, [15, 15, 25]);
+
+        let events = detector.update(&idle, at(30));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::GridOverloadCleared);
+        assert_eq!(events[0].duration_s, 5);
+    }
+}
@@ -0,0 +1,50 @@
+use std::io::{self, Write};
+
+const HIGHLIGHT: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// Bytes as space separated hex pairs
+pub fn spaced(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Classic 16 bytes per line dump with addresses starting at `addr`
+pub fn hexdump(addr: u16, bytes: &[u8], out: &mut impl Write) -> io::Result<()> {
+    hexdump_changes(addr, bytes, None, out)
+}
+
+/// Like [`hexdump`], bytes that differ from `previous` are shown in reverse video
+pub fn hexdump_changes(
+    addr: u16,
+    bytes: &[u8],
+    previous: Option<&[u8]>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for (line_index, line) in bytes.chunks(16).enumerate() {
+        let offset = line_index * 16;
+        write!(out, "    {:04x} ", addr as usize + offset)?;
+        for (index, byte) in line.iter().enumerate() {
+            let changed = previous
+                .and_then(|previous| previous.get(offset + index))
+                .is_some_and(|old| old != byte);
+            if changed {
+                write!(out, " {HIGHLIGHT}{byte:02x}{RESET}")?;
+            } else {
+                write!(out, " {byte:02x}")?;
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(out, "{:pad$}  {}", "", ascii, pad = (16 - line.len()) * 3)?;
+    }
+    Ok(())
+}
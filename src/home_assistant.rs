@@ -0,0 +1,89 @@
+use paho_mqtt::{Message, QOS_1};
+use serde_json::json;
+
+use crate::map_protocol::registers::RegisterMap;
+
+/// Retained MQTT discovery configs announcing every status register of a unit
+/// as a Home Assistant sensor reading its field from the unit topic
+pub fn discovery(
+    registers: &RegisterMap,
+    prefix: &str,
+    unit_id: &str,
+    topic: &str,
+    status_topic: &str,
+) -> Vec<Message> {
+    let object_id = topic.replace('/', "_");
+    let device = json!({
+        "identifiers": [object_id],
+        "name": format!("MAP {unit_id}"),
+        "manufacturer": "Microart",
+        "model": "MAP",
+    });
+    registers
+        .iter()
+        .filter(|register| register.is_status())
+        .map(|register| {
+            let mut config = json!({
+                "name": register.description,
+                "unique_id": format!("{object_id}_{}", register.name),
+                "object_id": format!("{object_id}_{}", register.name),
+                "state_topic": topic,
+                "value_template": format!("{{{{ value_json.{} }}}}", register.name),
+                "availability_topic": status_topic,
+                "availability_template":
+                    "{{ 'offline' if value_json.state == 'offline' else 'online' }}",
+                "device": device,
+            });
+            for (key, value) in [
+                ("unit_of_measurement", &register.unit),
+                ("device_class", &register.device_class),
+                ("state_class", &register.state_class),
+            ] {
+                if let Some(value) = value {
+                    config[key] = value.as_str().into();
+                }
+            }
+            Message::new_retained(
+                format!("{prefix}/sensor/{object_id}/{}/config", register.name),
+                config.to_string(),
+                QOS_1,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn announces_status_registers_as_sensors() {
+        let messages = discovery(
+            &RegisterMap::builtin(),
+            "homeassistant",
+            "1",
+            "map-invertor/1",
+            "map-invertor/1/status",
+        );
+        let message = messages
+            .iter()
+            .find(|message| message.topic() == "homeassistant/sensor/map-invertor_1/u_acc/config")
+            .unwrap();
+        assert!(message.retained());
+        let config: Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(config["name"], "Battery voltage");
+        assert_eq!(config["unique_id"], "map-invertor_1_u_acc");
+        assert_eq!(config["state_topic"], "map-invertor/1");
+        assert_eq!(config["value_template"], "{{ value_json.u_acc }}");
+        assert_eq!(config["availability_topic"], "map-invertor/1/status");
+        assert_eq!(config["unit_of_measurement"], "V");
+        assert_eq!(config["device_class"], "voltage");
+        assert_eq!(config["state_class"], "measurement");
+        assert_eq!(config["device"]["name"], "MAP 1");
+        // settings are not sensors
+        assert!(!messages
+            .iter()
+            .any(|message| message.topic().contains("/phase_config/")));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{io, ptr};
+
+use chrono::{DateTime, Local};
+use libc::{c_char, c_ulong, c_void};
+
+use crate::map_protocol::high_level::MapInfo;
+
+/// Segment with the JSON cache string read by the PHP web monitor
+const CACHE_KEY: libc::key_t = 2015;
+/// Segment with [`MapBat`]
+const BATMON_KEY: libc::key_t = 1998;
+/// Segment with an array of [`Bms`]
+const BMS_KEY: libc::key_t = 1997;
+const SEGMENT_SIZE: usize = 1024;
+const MAX_BMS_CELLS: usize = 32;
+
+/// `struct map_bat` of mapd, field for field
+#[repr(C)]
+struct MapBat {
+    battery_id: u8,
+    timestamp: c_ulong,
+    current: f32,
+    tbat: c_char,
+    ubat: f32,
+    imppt: f32,
+}
+
+/// `struct bms_struct` of mapd
+#[repr(C)]
+struct Bms {
+    cell_number: u8,
+    v: f32,
+    i: f32,
+    t: c_char,
+}
+
+/// SysV shared memory segment attached for the lifetime of the value.
+/// The segment itself is left in place on drop so readers keep the last data, as mapd did.
+struct Segment {
+    ptr: *mut u8,
+}
+
+impl Segment {
+    fn attach(key: libc::key_t) -> io::Result<Self> {
+        // SAFETY: plain syscalls, the returned address is checked before use
+        unsafe {
+            let id = libc::shmget(key, SEGMENT_SIZE, 0o644 | libc::IPC_CREAT);
+            if id == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let ptr = libc::shmat(id, ptr::null(), 0);
+            if ptr as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                ptr: ptr as *mut u8,
+            })
+        }
+    }
+
+    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= SEGMENT_SIZE);
+        // SAFETY: the segment is SEGMENT_SIZE bytes long and the range is checked above
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(offset), bytes.len()) }
+    }
+
+    fn write<T>(&mut self, offset: usize, value: T) {
+        assert!(offset + std::mem::size_of::<T>() <= SEGMENT_SIZE);
+        // SAFETY: in bounds as checked above, shmat memory is page aligned but the
+        // offset may not keep T aligned
+        unsafe { ptr::write_unaligned(self.ptr.add(offset) as *mut T, value) }
+    }
+
+    fn zero(&mut self) {
+        // SAFETY: the segment is SEGMENT_SIZE bytes long
+        unsafe { ptr::write_bytes(self.ptr, 0, SEGMENT_SIZE) }
+    }
+}
+
+// SAFETY: the mapping stays valid in any thread until shmdt, and writes need `&mut self`
+unsafe impl Send for Segment {}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: ptr was returned by shmat and is detached only once
+        unsafe {
+            libc::shmdt(self.ptr as *const c_void);
+        }
+    }
+}
+
+/// Keeps the shared memory segments of mapd up to date, so tools written for
+/// mapd (the PHP web monitor, battery monitor) keep working with the bridge.
+pub struct LegacyShm {
+    cache: Segment,
+    batmon: Segment,
+    bms: Segment,
+}
+
+impl LegacyShm {
+    pub fn attach() -> io::Result<Self> {
+        Ok(Self {
+            cache: Segment::attach(CACHE_KEY)?,
+            batmon: Segment::attach(BATMON_KEY)?,
+            bms: Segment::attach(BMS_KEY)?,
+        })
+    }
+
+    pub fn update(&mut self, map_info: &MapInfo) {
+        let now = Local::now();
+        let mut cache = cache_string(map_info, now).into_bytes();
+        cache.truncate(SEGMENT_SIZE - 1);
+        cache.push(0);
+        self.cache.write_bytes(0, &cache);
+
+        self.batmon.write(
+            0,
+            MapBat {
+                battery_id: 1,
+                timestamp: now.timestamp() as c_ulong,
+                current: map_info.battery_current,
+                tbat: map_info.temp_grad0 as c_char,
+                ubat: map_info.u_acc,
+                imppt: map_info.i_mppt_avg,
+            },
+        );
+
+        self.bms.zero();
+        for (index, cell) in map_info.bms.iter().take(MAX_BMS_CELLS).enumerate() {
+            self.bms.write(
+                index * std::mem::size_of::<Bms>(),
+                Bms {
+                    cell_number: cell.cell_number,
+                    v: cell.v,
+                    i: cell.i,
+                    t: cell.t as c_char,
+                },
+            );
+        }
+    }
+}
+
+/// The JSON string mapd kept in the cache segment: same keys, same order,
+/// every value quoted
+fn cache_string(map_info: &MapInfo, now: DateTime<Local>) -> String {
+    let m = map_info;
+    let fields: [(&str, String); 38] = [
+        ("time", now.format("%H:%M:%S").to_string()),
+        ("_MODE", m.mode.code().to_string()),
+        ("_Status_Char", m.status_char.to_string()),
+        ("_Uacc", format!("{:.1}", m.u_acc)),
+        ("_Iacc", m.i_acc.to_string()),
+        ("_PLoad", m.p_load.to_string()),
+        ("_F_Acc_Over", m.f_acc_over.to_string()),
+        ("_F_Net_Over", m.f_net_over.to_string()),
+        ("_UNET", m.u_net.to_string()),
+        ("_INET", m.i_net.to_string()),
+        ("_PNET", m.p_net.to_string()),
+        ("_TFNET", m.tf_net.to_string()),
+        ("_ThFMAP", m.th_f_map.to_string()),
+        ("_UOUTmed", m.u_ou_t_med.to_string()),
+        ("_TFNET_Limit", m.tf_net_limit.to_string()),
+        ("_UNET_Limit", m.u_net_limit.to_string()),
+        ("_RSErrSis", m.rs_err_sis.to_string()),
+        ("_RSErrJobM", m.rs_err_job_m.to_string()),
+        ("_RSErrJob", m.rs_err_job.to_string()),
+        ("_RSWarning", m.rs_warning.to_string()),
+        ("_Temp_Grad0", m.temp_grad0.to_string()),
+        ("_Temp_Grad2", m.temp_grad2.to_string()),
+        ("_INET_16_4", format!("{:.1}", m.i_net_16_4)),
+        ("_IAcc_med_A_u16", format!("{:.1}", m.i_acc_med_a_u16)),
+        ("Temp_off", m.temp_off.to_string()),
+        ("_E_NET", m.e_net.to_string()),
+        ("_E_ACC", m.e_acc.to_string()),
+        ("_E_ACC_CHARGE", m.e_acc_charge.to_string()),
+        ("_Uacc_optim", format!("{:.1}", m.u_acc_optim)),
+        ("_I_acc_avg", format!("{:.1}", m.i_acc_avg)),
+        ("_I_mppt_avg", format!("{:.1}", m.i_mppt_avg)),
+        ("_I2C_Err", m.i2_c_err.to_string()),
+        ("_Temp_Grad1", m.temp_grad1.to_string()),
+        ("_Relay1", m.relay1.to_string()),
+        ("_Relay2", m.relay2.to_string()),
+        ("_Flag_ECO", m.flag_eco.to_string()),
+        ("_RSErrDop", m.rs_err_dop.to_string()),
+        ("_flagUnet2", m.flag_u_net2.to_string()),
+    ];
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("\"{name}\":\"{value}\""))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_protocol::mode::MapModeExtended;
+    use chrono::TimeZone;
+
+    /// Keys in the order of mapd, integers as is and `%.1f` for the rest
+    #[test]
+    fn cache_string_matches_mapd() {
+        let map_info = MapInfo {
+            mode: MapModeExtended::PowerOnTranslatingExternalPower,
+            u_acc: 45.3,
+            i_acc: 12,
+            p_load: 850,
+            u_net: 229,
+            p_net: 900,
+            temp_grad0: -5,
+            i_net_16_4: 3.75,
+            i_acc_avg: -12.34,
+            relay2: 1,
+            ..Default::default()
+        };
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 7, 5, 9).unwrap();
+        assert_eq!(
+            cache_string(&map_info, now),
+            concat!(
+                r#"{"time":"07:05:09","_MODE":"3","_Status_Char":"0","_Uacc":"45.3","#,
+                r#""_Iacc":"12","_PLoad":"850","_F_Acc_Over":"0","_F_Net_Over":"0","#,
+                r#""_UNET":"229","_INET":"0","_PNET":"900","_TFNET":"0","_ThFMAP":"0","#,
+                r#""_UOUTmed":"0","_TFNET_Limit":"0","_UNET_Limit":"0","_RSErrSis":"0","#,
+                r#""_RSErrJobM":"0","_RSErrJob":"0","_RSWarning":"0","_Temp_Grad0":"-5","#,
+                r#""_Temp_Grad2":"0","_INET_16_4":"3.8","_IAcc_med_A_u16":"0.0","#,
+                r#""Temp_off":"0","_E_NET":"0","_E_ACC":"0","_E_ACC_CHARGE":"0","#,
+                r#""_Uacc_optim":"0.0","_I_acc_avg":"-12.3","_I_mppt_avg":"0.0","#,
+                r#""_I2C_Err":"0","_Temp_Grad1":"0","_Relay1":"0","_Relay2":"1","#,
+                r#""_Flag_ECO":"0","_RSErrDop":"0","_flagUnet2":"0"}"#
+            )
+        );
+    }
+}
@@ -1,20 +1,75 @@
-use log::{self, info, trace, warn};
+use log::{self, info, warn};
 use std::{
-    fmt::{format, Debug},
-    thread,
-    time::Duration,
+    collections::BTreeMap,
+    fmt::Debug,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use aggregate::Aggregate;
+use anyhow::{anyhow, bail, Context};
+use chrono::Local;
 use clap::{Args, Command, FromArgMatches, Parser, Subcommand};
 use clap_complete::{generate, Generator, Shell};
 use clap_duration::duration_range_value_parse;
 use duration_human::{DurationHuman, DurationHumanValidator};
-use map_protocol::high_level::{HighLevelProtocol, MapInfo};
+use map_protocol::{
+    capture::{self, CaptureFile},
+    high_level::{HighLevelProtocol, MapInfo},
+    registers::RegisterMap,
+    replay::Replay,
+    RetryPolicy, Transport,
+};
 
+use config::ConfigFile;
+use energy::{Energy, Ledger};
+use events::EventDetector;
+use legacy_shm::LegacyShm;
+use map_actor::MapHandle;
+use monitor::{Language, Monitored};
+use notify::Notifier;
 use paho_mqtt::{Message, QOS_1};
+use publish_policy::{FieldDeadband, PublishPolicy};
+use raw::HexBytes;
+use serde_json::Value;
+use signals::Signal;
+use soc::{Battery, Chemistry, SocLedger};
+use status::BridgeStatus;
+use stdout::{Format, Printer};
+use tariff::Tariff;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    task::JoinSet,
+};
+use unit::{try_publish, Unit, UnitContext, UnitSpec};
 
+mod aggregate;
+mod config;
+mod energy;
+mod envelope;
+mod events;
+mod hexdump;
+mod home_assistant;
+mod legacy_shm;
+mod map_actor;
 mod map_protocol;
+mod monitor;
+mod notify;
+mod prometheus;
+mod publish_policy;
+mod raw;
+mod signals;
+mod soc;
+mod state;
+mod status;
+mod stdout;
+mod systemd;
+mod tariff;
+mod unit;
+mod watchdog;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -23,15 +78,166 @@ struct Cli {
     mode: WorkingMode,
 }
 
+#[derive(Clone, Debug, Args)]
+struct MapPortArgs {
+    /// Map port, `ID=PATH` sets the unit id used in the MQTT topic (default is the position
+    /// starting from 1). Repeat or separate with commas to poll several MAPs
+    #[arg(
+        short = 'p',
+        long,
+        env,
+        required = true,
+        value_delimiter = ',',
+        value_name = "[ID=]PATH"
+    )]
+    map_port: Vec<UnitSpec>,
+    /// Map port speed
+    #[arg(short = 's', long, env)]
+    map_port_speed: u32,
+    /// How many times to repeat a command after a checksum, framing or timeout error
+    #[arg(long, env, default_value_t = RetryPolicy::default().retries)]
+    map_retries: u32,
+    /// Pause before every command sent to the MAP, e.g. `50ms`
+    #[arg(long, env, value_parser = DurationHuman::parse)]
+    map_inter_frame_delay: Option<DurationHuman>,
+    /// Append every frame exchanged with the MAP to this file, see `capture show`
+    #[arg(long, env = "MAP_CAPTURE", value_name = "FILE")]
+    capture: Option<PathBuf>,
+    /// Do not touch the serial port, play the MAP side of a `--capture` file back instead.
+    /// With several ports in the file only frames of the matching `--map-port` are used
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+    #[command(flatten)]
+    registers: RegisterMapArgs,
+}
+
+#[derive(Clone, Debug, Args)]
+struct RegisterMapArgs {
+    /// TOML file adding registers to the built-in register map or replacing them, see `registers`
+    #[arg(long, env = "MAP_REGISTER_MAP", value_name = "FILE")]
+    register_map: Option<PathBuf>,
+}
+
+impl RegisterMapArgs {
+    fn load(&self) -> anyhow::Result<Arc<RegisterMap>> {
+        RegisterMap::load(self.register_map.as_deref())
+    }
+}
+
+#[derive(Clone, Debug, Args)]
+struct BatteryTypeArgs {
+    /// Battery chemistry
+    #[arg(long, env, value_enum, default_value_t = Chemistry::LeadAcid)]
+    battery_chemistry: Chemistry,
+    /// Cells in series, by default the nominal voltage of the MAP over the cell voltage
+    #[arg(long, env)]
+    battery_cells: Option<u32>,
+}
+
+#[derive(Clone, Debug, Args)]
+struct BatteryArgs {
+    /// Battery capacity, the state of charge is estimated and published to `<topic>/soc` when set
+    #[arg(long, env, value_name = "AH")]
+    battery_capacity: Option<f64>,
+    #[command(flatten)]
+    battery_type: BatteryTypeArgs,
+    /// File keeping the state of charge across restarts
+    #[arg(long, env = "MAP_SOC_STATE", value_name = "FILE")]
+    soc_state: Option<PathBuf>,
+}
+
+impl BatteryArgs {
+    fn battery(&self, nominal_voltage: f32) -> Option<Battery> {
+        let capacity = self.battery_capacity?;
+        Some(Battery::new(
+            capacity,
+            self.battery_type.battery_chemistry,
+            self.battery_type.battery_cells,
+            nominal_voltage,
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Args)]
+struct PublishArgs {
+    /// Polling interval
+    #[arg(
+        long, default_value="10s",
+        value_parser = duration_range_value_parse!(min: 1s, max: 10min)
+    )]
+    interval: DurationHuman,
+    /// Publish a field change only when it exceeds this threshold, e.g. `u_acc=0.5` or `p_load=10%`.
+    /// Can be repeated. Fields without a threshold are published on any change
+    #[arg(long = "deadband", value_name = "FIELD=THRESHOLD")]
+    deadbands: Vec<FieldDeadband>,
+    /// Do not publish changes more often than this, by default every significant change is published
+    #[arg(
+        long,
+        value_parser = duration_range_value_parse!(min: 1s, max: 1h)
+    )]
+    min_publish_interval: Option<DurationHuman>,
+    /// Publish even if nothing changed when this time has passed since the last publish
+    #[arg(
+        long, default_value="5min",
+        value_parser = duration_range_value_parse!(min: 1s, max: 1day)
+    )]
+    max_publish_interval: DurationHuman,
+    /// TOML file overriding the polling and publishing settings above, read again on SIGHUP
+    #[arg(long, env = "MAP_BRIDGE_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+impl PublishArgs {
+    fn config_file(&self) -> anyhow::Result<ConfigFile> {
+        match &self.config {
+            Some(path) => ConfigFile::load(path),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    /// Polling interval and publish policy from the command line overridden by the config file,
+    /// deadbands may be set for the fields of `registers` too
+    fn load(&self, registers: &RegisterMap) -> anyhow::Result<(Duration, PublishPolicy)> {
+        let file = self.config_file()?;
+        let interval = file.interval.unwrap_or(Duration::from(&self.interval));
+        let mut deadbands = self.deadbands.clone();
+        deadbands.extend(file.deadbands()?);
+        let publish_policy = PublishPolicy::new(
+            &deadbands,
+            file.min_publish_interval.unwrap_or(
+                self.min_publish_interval
+                    .as_ref()
+                    .map(Duration::from)
+                    .unwrap_or_default(),
+            ),
+            file.max_publish_interval
+                .unwrap_or(Duration::from(&self.max_publish_interval)),
+            &known_fields(registers)?,
+        )?;
+        Ok((interval, publish_policy))
+    }
+
+    /// `[notify]` of the config file
+    fn notifier(&self, registers: &RegisterMap) -> anyhow::Result<Notifier> {
+        Notifier::new(self.config_file()?.notify, &known_fields(registers)?)
+    }
+
+    /// `[tariff]` of the config file
+    fn tariff(&self) -> anyhow::Result<Option<Tariff>> {
+        let tariff = self.config_file()?.tariff;
+        if let Some(tariff) = &tariff {
+            tariff.check()?;
+        }
+        Ok(tariff)
+    }
+}
+
 #[derive(Clone, Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum WorkingMode {
     Mqtt {
-        /// Map port
-        #[arg(short = 'p', long, env)]
-        map_port: String,
-        /// Map port peed
-        #[arg(short = 's', long, env)]
-        map_port_speed: u32,
+        #[command(flatten)]
+        map: MapPortArgs,
         /// MQTT broker hostname
         #[arg(short, long, env)]
         mqtt_hostname: String,
@@ -44,29 +250,127 @@ enum WorkingMode {
         /// MQTT broker password
         #[arg(long, env)]
         mqtt_password: String,
-        /// MQTT broker topic
+        /// MQTT broker topic, overrides `<prefix>/<unit id>` when a single MAP is polled
         #[arg(long, env)]
         mqtt_topic: Option<String>,
+        /// Every MAP publishes to `<prefix>/<unit id>`, with several MAPs the sums go to
+        /// `<prefix>/total` and the bridge status to `<prefix>/status`
+        #[arg(long, env, default_value = "map-invertor")]
+        mqtt_topic_prefix: String,
         /// my id, default is "map-invertor-mqtt-bridge"
         #[arg(long, env)]
         mqtt_id: Option<String>,
-        /// Polling interval
+        #[command(flatten)]
+        publish: PublishArgs,
+        /// Start recovering when the polled values have not changed for this long
         #[arg(
-        long, default_value="10s",
-        value_parser = duration_range_value_parse!(min: 1s, max: 10min)
+        long, default_value="5min",
+        value_parser = duration_range_value_parse!(min: 10s, max: 1day)
     )]
-        interval: DurationHuman,
+        stale_timeout: DurationHuman,
+        /// Time to wait for changing values after each recovery step before trying the next one
+        #[arg(
+        long, default_value="1min",
+        value_parser = duration_range_value_parse!(min: 1s, max: 1h)
+    )]
+        recovery_step_timeout: DurationHuman,
+        /// How many times to go through reopening the port, re-identifying the MAP
+        /// and reconnecting MQTT before exiting
+        #[arg(long, default_value_t = 1)]
+        recovery_rounds: u32,
+        /// Maintain the SysV shared memory segments of mapd (keys 2015, 1998 and 1997)
+        /// for tools written for it, like the PHP web monitor
+        #[arg(long, env)]
+        legacy_shm: bool,
+        /// Publish Home Assistant MQTT discovery configs for every register under this prefix,
+        /// usually `homeassistant`
+        #[arg(long, env, value_name = "PREFIX")]
+        ha_discovery_prefix: Option<String>,
+        /// File keeping the daily, monthly and lifetime energy totals across restarts
+        #[arg(long, env = "MAP_ENERGY_STATE", value_name = "FILE")]
+        energy_state: Option<PathBuf>,
+        /// Watt-hours per step of the raw `e_net`, `e_acc` and `e_acc_charge` counters,
+        /// mapd does not document it; energy totals are published only when it is given
+        #[arg(long, env)]
+        energy_counter_wh: Option<f64>,
+        #[command(flatten)]
+        battery: BatteryArgs,
+        /// Append grid, charging, relay and overload events to this JSON Lines file
+        #[arg(long, env = "MAP_EVENT_LOG", value_name = "FILE")]
+        event_log: Option<PathBuf>,
+        /// Serve the latest values of every register as Prometheus metrics at
+        /// `http://ADDR/metrics`, e.g. `0.0.0.0:9650`
+        #[arg(long, env, value_name = "ADDR")]
+        prometheus_listen: Option<SocketAddr>,
     },
     Stdout {
-        /// Map port
-        #[arg(short = 'p', long, env)]
-        map_port: String,
-        /// Map port speed
-        #[arg(short = 's', long, env)]
-        map_port_speed: u32,
-        /// When not using MQTT dump to stdout as JSON instead of human readable text
-        #[arg(short, long)]
+        #[command(flatten)]
+        map: MapPortArgs,
+        /// Same as `--format json`
+        #[arg(short, long, conflicts_with = "format")]
         json_output: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+        /// Keep polling and printing every `--interval` until interrupted
+        #[arg(long)]
+        follow: bool,
+        /// Polling interval of `--follow`
+        #[arg(
+            long, default_value="10s",
+            value_parser = duration_range_value_parse!(min: 1s, max: 1day)
+        )]
+        interval: DurationHuman,
+        /// Print only these fields, e.g. `u_acc,p_load`
+        #[arg(long, value_delimiter = ',', value_name = "FIELD")]
+        fields: Vec<String>,
+    },
+    /// Show a live dashboard of every MAP in the terminal
+    Monitor {
+        #[command(flatten)]
+        map: MapPortArgs,
+        /// Refresh interval
+        #[arg(
+            long, default_value="2s",
+            value_parser = duration_range_value_parse!(min: 1s, max: 1h)
+        )]
+        interval: DurationHuman,
+        /// Language of the dashboard
+        #[arg(long, env = "MAP_MONITOR_LANG", value_enum, default_value_t = Language::En)]
+        lang: Language,
+        // the battery gauge runs from empty to the end of charge voltage of the chemistry
+        #[command(flatten)]
+        battery: BatteryTypeArgs,
+    },
+    /// Print an example systemd unit running the bridge in MQTT mode
+    SystemdUnit {
+        /// File with MAP_PORT, MQTT_HOSTNAME and other settings as environment variables
+        #[arg(long, default_value = "/etc/default/map-invertor-mqtt-bridge")]
+        env_file: String,
+        /// User to run the bridge as, must have access to the MAP port
+        #[arg(long)]
+        user: Option<String>,
+        /// Restart the bridge when no successful poll happened for this long
+        #[arg(
+        long, default_value="2min",
+        value_parser = duration_range_value_parse!(min: 10s, max: 1day)
+    )]
+        watchdog: DurationHuman,
+    },
+    /// Read or write MAP memory directly, for firmware investigation
+    Raw {
+        #[command(subcommand)]
+        action: RawAction,
+    },
+    /// Print the register map as a markdown table
+    Registers {
+        #[command(flatten)]
+        registers: RegisterMapArgs,
+    },
+    /// Work with files written by `--capture`
+    Capture {
+        #[command(subcommand)]
+        action: CaptureAction,
     },
     Completion {
         /// generate autcompletion script for shell
@@ -75,10 +379,123 @@ enum WorkingMode {
     },
 }
 
+impl MapPortArgs {
+    fn open(&self, path: &str) -> anyhow::Result<HighLevelProtocol> {
+        let port: Box<dyn Transport> = match &self.replay {
+            Some(file) => {
+                info!("replaying {} as map port {}", file.display(), path);
+                Box::new(Replay::for_port(capture::read(file)?, path))
+            }
+            None => {
+                let port = serialport::new(path, self.map_port_speed)
+                    .timeout(Duration::from_secs(20))
+                    .open()?;
+                info!("Map port {} opened", path);
+                Box::new(port)
+            }
+        };
+        let retry_policy = RetryPolicy {
+            retries: self.map_retries,
+            inter_frame_delay: self
+                .map_inter_frame_delay
+                .as_ref()
+                .map(Duration::from)
+                .unwrap_or_default(),
+        };
+        let capture = self
+            .capture
+            .as_deref()
+            .map(|file| CaptureFile::open(file, path))
+            .transpose()?;
+        Ok(HighLevelProtocol::new(port, retry_policy, capture)?.with_registers(self.registers()?))
+    }
+
+    fn registers(&self) -> anyhow::Result<Arc<RegisterMap>> {
+        self.registers.load()
+    }
+
+    /// Opens the port and starts the thread owning it
+    fn spawn(&self, id: &str, path: &str) -> anyhow::Result<MapHandle> {
+        let args = self.clone();
+        let path = path.to_owned();
+        MapHandle::spawn(format!("map-{id}"), Box::new(move || args.open(&path)))
+    }
+}
+
+#[derive(Clone, Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum RawAction {
+    /// Dump a memory range
+    Read {
+        #[command(flatten)]
+        map: MapPortArgs,
+        /// Start address, decimal or `0x` hex
+        #[arg(long, value_parser = raw::parse_number)]
+        addr: u16,
+        /// Number of bytes to read
+        #[arg(long, default_value = "0x100", value_parser = raw::parse_number)]
+        len: u16,
+        /// List the known registers in the range with their decoded values
+        #[arg(long)]
+        decode: bool,
+        /// Keep polling the range and print it whenever it changes, changed bytes are highlighted
+        #[arg(long)]
+        watch: bool,
+        /// Polling interval of `--watch`
+        #[arg(
+            long, default_value="1s",
+            value_parser = duration_range_value_parse!(min: 1s, max: 1h)
+        )]
+        interval: DurationHuman,
+    },
+    /// Write bytes to memory and dump the range read back
+    Write {
+        #[command(flatten)]
+        map: MapPortArgs,
+        /// Start address, decimal or `0x` hex
+        #[arg(long, value_parser = raw::parse_number)]
+        addr: u16,
+        /// Bytes to write as hex, e.g. `01 ff 20` or `01ff20`
+        #[arg(long)]
+        bytes: HexBytes,
+        /// Write even when the firmware is not recognised or a setting is outside
+        /// the limits stored in EEPROM
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum CaptureAction {
+    /// Print a capture file in a readable form
+    Show { file: PathBuf },
+}
+
+/// Names of the fields of a sample, including the registers of a `--register-map` file
+fn known_fields(registers: &RegisterMap) -> anyhow::Result<Vec<String>> {
+    let Value::Object(fields) = serde_json::to_value(MapInfo::default())? else {
+        bail!("MapInfo is not serialized as an object");
+    };
+    Ok(fields
+        .into_iter()
+        .map(|(field, _)| field)
+        .chain(registers.iter().map(|register| register.name.clone()))
+        .collect())
+}
+
+/// The only port of a command working with one MAP
+fn single_port(map: &MapPortArgs) -> anyhow::Result<(String, String)> {
+    match unit::resolve(&map.map_port)?.as_slice() {
+        [port] => Ok(port.clone()),
+        _ => bail!("give a single --map-port"),
+    }
+}
+
 fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
     generate(gen, cmd, cmd.get_name().to_string(), &mut std::io::stdout());
 }
-fn main() -> anyhow::Result<()> {
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let cli = Command::new("CLI");
@@ -99,105 +516,409 @@ fn main() -> anyhow::Result<()> {
             let mut cli = Cli::augment_args(cli);
             print_completions(shell, &mut cli);
         }
+        WorkingMode::Raw { action } => match action {
+            RawAction::Read {
+                map,
+                addr,
+                len,
+                decode,
+                watch,
+                interval,
+            } => {
+                let (id, path) = single_port(&map)?;
+                let registers = map.registers()?;
+                let decode = decode.then_some(registers.as_ref());
+                let handle = map.spawn(&id, &path)?;
+                if watch {
+                    raw::watch(&handle, addr, len, decode, Duration::from(&interval)).await?;
+                } else {
+                    raw::read(&handle, addr, len, decode).await?;
+                }
+            }
+            RawAction::Write {
+                map,
+                addr,
+                bytes,
+                force,
+            } => {
+                let (id, path) = single_port(&map)?;
+                let handle = map.spawn(&id, &path)?;
+                raw::write(&handle, addr, bytes.0, force).await?;
+            }
+        },
+        WorkingMode::Registers { registers } => {
+            registers
+                .load()?
+                .write_markdown(&mut std::io::stdout().lock())?;
+        }
+        WorkingMode::Capture {
+            action: CaptureAction::Show { file },
+        } => {
+            let records = capture::read(&file)?;
+            capture::show(&records, &mut std::io::stdout().lock())?;
+        }
+        WorkingMode::SystemdUnit {
+            env_file,
+            user,
+            watchdog,
+        } => {
+            let exe = std::env::current_exe()?;
+            print!(
+                "{}",
+                systemd::unit_file(
+                    &exe.to_string_lossy(),
+                    &env_file,
+                    user.as_deref(),
+                    Duration::from(&watchdog)
+                )
+            );
+        }
+        WorkingMode::Monitor {
+            map,
+            interval,
+            lang,
+            battery,
+        } => {
+            let mut units = Vec::new();
+            for (id, path) in unit::resolve(&map.map_port)? {
+                let handle = map.spawn(&id, &path)?;
+                let identity = handle
+                    .identify()
+                    .await
+                    .with_context(|| format!("cannot identify MAP {id} at {path}"))?;
+                let chemistry = battery.battery_chemistry;
+                units.push(Monitored {
+                    id,
+                    map: handle,
+                    chemistry,
+                    cells: chemistry.cells(battery.battery_cells, identity.battery_voltage()),
+                });
+            }
+            monitor::run(&units, Duration::from(&interval), lang).await?;
+        }
         WorkingMode::Stdout {
-            map_port,
-            map_port_speed,
+            map,
             json_output,
+            format,
+            follow,
+            interval,
+            fields,
         } => {
-            let port = serialport::new(map_port, map_port_speed)
-                .timeout(Duration::from_secs(20))
-                .open()?;
-            let mut protocol = HighLevelProtocol::new(port)?;
-
-            let eeprom = protocol.read_eeprom()?;
-            if eeprom[0] != 3 {
-                bail!("MAP not found");
+            let format = if json_output { Format::Json } else { format };
+            let registers = map.registers()?;
+            let mut printer = Printer::new(format, fields, &known_fields(&registers)?)?;
+            let mut handles = Vec::new();
+            for (id, path) in unit::resolve(&map.map_port)? {
+                let handle = map.spawn(&id, &path)?;
+                handle.identify().await?;
+                handles.push((id, handle));
+            }
+            // registered once, so Ctrl-C while a MAP does not answer is not lost
+            let mut interrupt = signal(SignalKind::interrupt())?;
+            'follow: loop {
+                let timestamp = Local::now();
+                let mut infos = BTreeMap::new();
+                for (id, handle) in &handles {
+                    let map_info = tokio::select! {
+                        map_info = handle.read_status() => map_info,
+                        _ = interrupt.recv() => break 'follow,
+                    };
+                    match map_info {
+                        Ok(map_info) => {
+                            infos.insert(id.clone(), map_info);
+                        }
+                        // a single failed poll does not end the stream
+                        Err(error) if follow => warn!("cannot read MAP {}: {:#}", id, error),
+                        Err(error) => return Err(error),
+                    }
+                }
+                printer.print(&mut std::io::stdout().lock(), timestamp, &infos)?;
+                if !follow {
+                    break;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from(&interval)) => {}
+                    _ = interrupt.recv() => break,
+                }
             }
-            let map_info = protocol.read_status(&eeprom)?;
-            if json_output {
-                print!(
-                    "{}",
-                    serde_json::to_string_pretty(&map_info)
-                        .expect("Cannot serialize map_info value")
-                );
-            } else {
-                dbg!(map_info);
-            };
         }
         WorkingMode::Mqtt {
-            map_port,
-            map_port_speed,
+            map,
             mqtt_hostname,
             mqtt_port,
             mqtt_username,
             mqtt_password,
             mqtt_topic,
+            mqtt_topic_prefix,
             mqtt_id,
-            interval,
+            publish,
+            stale_timeout,
+            recovery_step_timeout,
+            recovery_rounds,
+            legacy_shm,
+            ha_discovery_prefix,
+            energy_state,
+            energy_counter_wh,
+            battery,
+            event_log,
+            prometheus_listen,
         } => {
-            let port = serialport::new(&map_port, map_port_speed)
-                .timeout(Duration::from_secs(20))
-                .open()?;
-            info!("Map port {} opened", map_port);
-            let mut map_protocol = HighLevelProtocol::new(port)?;
-            let mqtt_id = mqtt_id.unwrap_or("map-invertor-mqtt-bridge".into());
+            // fail early on a broken config file, every unit loads it again on its own
+            let registers = map.registers()?;
+            publish.load(&registers)?;
+            let mut tariff = publish.tariff()?;
+            let ports = unit::resolve(&map.map_port)?;
+            let mut notifier = publish.notifier(&registers)?;
+            let signals = signals::listen()?;
+            // subscribed before anything slow, so a stop during the start is not lost
+            let mut shutdown = signals.subscribe();
+            if mqtt_topic.is_some() && ports.len() > 1 {
+                bail!("--mqtt-topic can only be used with a single MAP, use --mqtt-topic-prefix");
+            }
+
+            let mut ledger = Ledger::open(energy_state.as_deref(), energy_counter_wh)?;
+            let mut soc_ledger = SocLedger::open(battery.soc_state.as_deref())?;
+            let mut batteries = BTreeMap::new();
+
+            let mut units = Vec::with_capacity(ports.len());
+            for (index, (id, path)) in ports.into_iter().enumerate() {
+                let handle = map.spawn(&id, &path)?;
+                let Ok(identity) = shutdown.unless_shutdown(handle.identify()).await else {
+                    info!("stopped while identifying MAP {}", id);
+                    return Ok(());
+                };
+                let identity =
+                    identity.with_context(|| format!("cannot identify MAP {id} at {path}"))?;
+                if let Some(battery) = battery.battery(identity.battery_voltage()) {
+                    batteries.insert(id.clone(), battery);
+                }
+                // mapd served a single MAP, so its segments are fed by the first one
+                let legacy_shm = if legacy_shm && index == 0 {
+                    Some(LegacyShm::attach().context("cannot attach legacy shared memory")?)
+                } else {
+                    None
+                };
+                let topic = match &mqtt_topic {
+                    Some(topic) => topic.clone(),
+                    None => format!("{mqtt_topic_prefix}/{id}"),
+                };
+                units.push(Unit {
+                    id,
+                    port: path,
+                    topic,
+                    map: handle,
+                    legacy_shm,
+                });
+            }
+            let single = units.len() == 1;
+            let status_topic = if single {
+                units[0].status_topic()
+            } else {
+                status::status_topic(&mqtt_topic_prefix)
+            };
+            let total_topic = format!("{mqtt_topic_prefix}/total");
 
+            let mqtt_id = mqtt_id.unwrap_or("map-invertor-mqtt-bridge".into());
             let url: String = format!("tcp://{mqtt_hostname}:{mqtt_port}");
 
-            let cli = paho_mqtt::Client::new((url.clone(), mqtt_id))?;
+            let cli = paho_mqtt::AsyncClient::new((url.clone(), mqtt_id))?;
             let conn_opts = paho_mqtt::ConnectOptionsBuilder::new()
                 .keep_alive_interval(Duration::from_secs(20))
                 .user_name(&mqtt_username)
                 .password(&mqtt_password)
                 .clean_session(true)
+                .will_message(Message::new_retained(
+                    &status_topic,
+                    serde_json::to_vec(&BridgeStatus::offline("connection lost".into()))?,
+                    QOS_1,
+                ))
                 .finalize();
 
-            cli.connect(conn_opts)?;
+            let Ok(connected) = shutdown.unless_shutdown(cli.connect(conn_opts)).await else {
+                info!("stopped while connecting to MQTT broker at {}", url);
+                return Ok(());
+            };
+            connected?;
             info!("connected to MQTT broker at {}", url);
-            let eeprom = map_protocol.read_eeprom()?;
+            if !single {
+                status::publish(&cli, &status_topic, &BridgeStatus::online()).await?;
+            }
 
-            if eeprom[0] != 3 {
-                bail!("MAP not found");
+            let unit_topics: BTreeMap<String, String> = units
+                .iter()
+                .map(|unit| (unit.id.clone(), unit.topic.clone()))
+                .collect();
+            let (samples_tx, mut samples) = mpsc::channel(16);
+            let (reconnect, mut reconnect_requests) = mpsc::channel(1);
+            let health = Arc::new(Mutex::new(systemd::Health::new(
+                units.iter().map(|unit| unit.id.clone()),
+            )));
+            let mut tasks = JoinSet::new();
+            for unit in units {
+                let context = UnitContext {
+                    cli: cli.clone(),
+                    reconnect: reconnect.clone(),
+                    publish: publish.clone(),
+                    stale_timeout: Duration::from(&stale_timeout),
+                    recovery_step_timeout: Duration::from(&recovery_step_timeout),
+                    recovery_rounds,
+                    samples: samples_tx.clone(),
+                    registers: registers.clone(),
+                    discovery_prefix: ha_discovery_prefix.clone(),
+                    health: health.clone(),
+                };
+                tasks.spawn(unit.run(context, signals.subscribe()));
             }
-            let topic = &mqtt_topic.unwrap_or("map-invertor/1".into());
-            let mut count = 0;
-            let mut consecutive_same_reads = 0;
-
-            let max_consecutive_reads = 300 / Duration::from(&interval).as_secs();
-
-            let mut prev_map_info = MapInfo::default();
-            loop {
-                let map_info = map_protocol.read_status(&eeprom)?;
-                // let map_info = MapInfo::default();
-                if prev_map_info != map_info {
-                    let msg =
-                        Message::new_retained(topic, serde_json::to_vec(&map_info).unwrap(), QOS_1);
-                    cli.publish(msg)?;
-                    prev_map_info = map_info;
-                    consecutive_same_reads = 0;
-                } else {
-                    consecutive_same_reads += 1;
-                }
-                count = (count + 1) % 10;
-                trace!("map info: {:?}", &prev_map_info);
-                trace!("count: {}", count);
-                trace!("consecutive_same_reads: {}", consecutive_same_reads);
-                if consecutive_same_reads > max_consecutive_reads {
-                    warn!(
-                        "map info not changed for {} seconds and {} iterations",
-                        Duration::from(&interval).as_secs() * count,
-                        count
-                    );
-
-                    bail!(
-                        "map info not changed for {} seconds and {} iterations, restarting",
-                        Duration::from(&interval).as_secs() * count,
-                        count
-                    );
+            drop(samples_tx);
+            drop(reconnect);
+            systemd::ready();
+
+            let aggregate = Arc::new(Mutex::new(Aggregate::new(Duration::from(&stale_timeout))));
+            if let Some(addr) = prometheus_listen {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("cannot listen for Prometheus at {addr}"))?;
+                prometheus::serve(listener, registers.clone(), aggregate.clone())?;
+            }
+            let mut prev_totals = None;
+            let mut prev_energy = BTreeMap::new();
+            let mut detectors = BTreeMap::new();
+            let mut last_reconnect: Option<Instant> = None;
+            let stopped_by: anyhow::Result<&str> = loop {
+                tokio::select! {
+                    Some((id, map_info)) = samples.recv() => {
+                        let report = ledger.update(&id, &map_info, Local::now()).map(Energy::report);
+                        if let Some(report) = report.filter(|report| prev_energy.get(&id) != Some(report)) {
+                            let message = Message::new_retained(
+                                format!("{}/energy", unit_topics[&id]),
+                                serde_json::to_vec(&report)?,
+                                QOS_1,
+                            );
+                            if try_publish(&cli, message).await {
+                                prev_energy.insert(id.clone(), report);
+                            }
+                        }
+                        let events = detectors
+                            .entry(id.clone())
+                            .or_insert_with(|| EventDetector::new(id.clone()))
+                            .update(&map_info, Local::now());
+                        for event in &events {
+                            info!("unit {}: {:?}", id, event.kind);
+                            try_publish(&cli, Message::new(
+                                format!("{}/events", unit_topics[&id]),
+                                serde_json::to_vec(event)?,
+                                QOS_1,
+                            ))
+                            .await;
+                        }
+                        if let (Some(path), false) = (&event_log, events.is_empty()) {
+                            if let Err(error) = events::append(path, &events) {
+                                warn!("{:#}", error);
+                            }
+                        }
+                        if let Some(battery) = batteries.get(&id) {
+                            let report = soc_ledger.update(&id, battery, &map_info, Local::now());
+                            try_publish(&cli, Message::new_retained(
+                                format!("{}/soc", unit_topics[&id]),
+                                serde_json::to_vec(&report)?,
+                                QOS_1,
+                            ))
+                            .await;
+                        }
+                        for notification in notifier.check(&id, &map_info, Instant::now()) {
+                            notifier.send(&notification);
+                        }
+                        if let Some(tariff) = &tariff {
+                            let cost = ledger.cost(&id, tariff, &map_info, Local::now());
+                            try_publish(&cli, Message::new_retained(
+                                format!("{}/cost", unit_topics[&id]),
+                                serde_json::to_vec(cost)?,
+                                QOS_1,
+                            ))
+                            .await;
+                        }
+                        let mut totals = {
+                            let mut aggregate = aggregate.lock().unwrap();
+                            aggregate.update(id, map_info, Instant::now());
+                            aggregate.totals()
+                        };
+                        totals.energy = ledger.totals();
+                        if !single && prev_totals.as_ref() != Some(&totals) {
+                            let message = Message::new_retained(
+                                &total_topic,
+                                serde_json::to_vec(&totals)?,
+                                QOS_1,
+                            );
+                            if try_publish(&cli, message).await {
+                                prev_totals = Some(totals);
+                            }
+                        }
+                    }
+                    Some(()) = reconnect_requests.recv() => {
+                        // every stale unit asks, once per recovery step is enough
+                        let step = Duration::from(&recovery_step_timeout);
+                        if last_reconnect.is_none_or(|at| at.elapsed() >= step) {
+                            last_reconnect = Some(Instant::now());
+                            if let Err(error) = cli.disconnect(None).await {
+                                warn!("cannot disconnect from MQTT broker: {}", error);
+                            }
+                            match cli.reconnect().await {
+                                Ok(_) => info!("reconnected to MQTT broker at {}", url),
+                                Err(error) => warn!("cannot reconnect to MQTT broker: {}", error),
+                            }
+                        }
+                    }
+                    Some(result) = tasks.join_next() => {
+                        break match result {
+                            Ok(Ok(())) => Err(anyhow!("polling task stopped")),
+                            Ok(Err(error)) => Err(error),
+                            Err(error) => Err(anyhow!("polling task failed: {error}")),
+                        };
+                    }
+                    signal = shutdown.recv() => match signal {
+                        Some(Signal::Shutdown(name)) => break Ok(name),
+                        Some(Signal::Reload) => {
+                            info!("reloading configuration");
+                            match publish.tariff() {
+                                Ok(new_tariff) => tariff = new_tariff,
+                                Err(error) => warn!("cannot reload tariff: {:#}", error),
+                            }
+                            match publish.notifier(&registers) {
+                                Ok(new_notifier) => notifier = new_notifier,
+                                Err(error) => warn!("cannot reload notify rules: {:#}", error),
+                            }
+                        }
+                        None => break Err(anyhow!("signal handler stopped")),
+                    },
                 }
+            };
 
-                thread::sleep(Duration::from(&interval));
+            ledger.flush();
+            soc_ledger.flush();
+            match &stopped_by {
+                Ok(name) => info!("stopped by {}", name),
+                // the units got no signal, stop them the same way
+                Err(_) => {
+                    signals.send(Signal::Shutdown("error in another unit"));
+                }
+            }
+            systemd::stopping();
+            while let Some(result) = tasks.join_next().await {
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => warn!("{:#}", error),
+                    Err(error) => warn!("polling task failed: {}", error),
+                }
+            }
+            let stopped_by = stopped_by?;
+            if !single {
+                let status = BridgeStatus::offline(format!("stopped by {stopped_by}"));
+                if let Err(error) = status::publish(&cli, &status_topic, &status).await {
+                    warn!("cannot publish status: {}", error);
+                }
             }
+            // waits for messages still in flight
+            cli.disconnect(None).await?;
+            log::logger().flush();
         }
     }
     Ok(())
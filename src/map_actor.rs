@@ -0,0 +1,308 @@
+use std::{io, thread};
+
+use anyhow::{anyhow, bail};
+use log::{debug, info, warn};
+use snafu::ResultExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::map_protocol::{
+    firmware,
+    high_level::{HighLevelProtocol, Identity, MapInfo},
+    IOSnafu, MapError, ProtocolStats,
+};
+
+/// Opens the port of a MAP, called once at start and again on every reopen
+pub type Opener = Box<dyn FnMut() -> anyhow::Result<HighLevelProtocol> + Send>;
+
+type Reply<T> = oneshot::Sender<Result<T, MapError>>;
+
+enum MapCommand {
+    Identify(Reply<Identity>),
+    ReadStatus(Reply<MapInfo>),
+    ReadMemory {
+        addr: u16,
+        len: u16,
+        reply: Reply<Vec<u8>>,
+    },
+    WriteMemory {
+        addr: u16,
+        bytes: Vec<u8>,
+        force: bool,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Stats(oneshot::Sender<ProtocolStats>),
+    Reopen(oneshot::Sender<anyhow::Result<()>>),
+}
+
+/// Owns the serial port of one MAP on a dedicated thread and runs the commands
+/// sent through [`MapHandle`]s one after another, so async tasks never block
+/// on the port and two exchanges never interleave on the line.
+struct MapActor {
+    /// `None` after a failed reopen, opened again by the next command
+    protocol: Option<HighLevelProtocol>,
+    open: Opener,
+    /// Result of the last successful identification, needed to decode the status
+    identity: Option<Identity>,
+}
+
+impl MapActor {
+    fn protocol(&mut self) -> Result<&mut HighLevelProtocol, MapError> {
+        if self.protocol.is_none() {
+            match (self.open)() {
+                Ok(protocol) => self.protocol = Some(protocol),
+                Err(error) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("{error:#}"),
+                    ))
+                    .context(IOSnafu)
+                }
+            }
+        }
+        Ok(self.protocol.as_mut().expect("opened above"))
+    }
+
+    /// Closes the port before opening it again, the serial port is opened
+    /// exclusively and a second open of the same device fails
+    fn reopen(&mut self) -> anyhow::Result<()> {
+        if let Some(mut protocol) = self.protocol.take() {
+            if let Err(error) = protocol.flush() {
+                warn!("cannot flush map port: {}", error);
+            }
+        }
+        self.protocol = Some((self.open)()?);
+        Ok(())
+    }
+
+    fn identify(&mut self) -> Result<Identity, MapError> {
+        let identity = self.protocol()?.identify()?;
+        if self.identity.as_ref().map(|known| &known.firmware) != Some(&identity.firmware) {
+            info!("MAP firmware: {}", identity.firmware);
+        }
+        self.identity = Some(identity.clone());
+        Ok(identity)
+    }
+
+    fn identity(&mut self) -> Result<Identity, MapError> {
+        match &self.identity {
+            Some(identity) => Ok(identity.clone()),
+            None => self.identify(),
+        }
+    }
+
+    /// Writes unless the firmware check refuses it, `force` skips the check
+    fn write_memory(&mut self, addr: u16, bytes: &[u8], force: bool) -> anyhow::Result<()> {
+        let identity = self.identity()?;
+        if let Err(reason) =
+            firmware::check_write(&identity.firmware, &identity.eeprom, addr, bytes)
+        {
+            if !force {
+                bail!("refusing to write: {reason}, use --force to write anyway");
+            }
+            warn!("writing anyway: {}", reason);
+        }
+        Ok(self.protocol()?.write_memory(addr, bytes)?)
+    }
+
+    fn handle(&mut self, command: MapCommand) {
+        // a dropped reply only means the caller is gone, the command is done anyway
+        match command {
+            MapCommand::Identify(reply) => {
+                let _ = reply.send(self.identify());
+            }
+            MapCommand::ReadStatus(reply) => {
+                let result = self
+                    .identity()
+                    .and_then(|identity| self.protocol()?.read_status(&identity));
+                let _ = reply.send(result);
+            }
+            MapCommand::ReadMemory { addr, len, reply } => {
+                let result = self
+                    .protocol()
+                    .and_then(|protocol| protocol.read_memory(addr, len));
+                let _ = reply.send(result);
+            }
+            MapCommand::WriteMemory {
+                addr,
+                bytes,
+                force,
+                reply,
+            } => {
+                let _ = reply.send(self.write_memory(addr, &bytes, force));
+            }
+            MapCommand::Stats(reply) => {
+                let stats = self
+                    .protocol
+                    .as_ref()
+                    .map(HighLevelProtocol::stats)
+                    .unwrap_or_default();
+                let _ = reply.send(stats);
+            }
+            MapCommand::Reopen(reply) => {
+                let _ = reply.send(self.reopen());
+            }
+        }
+    }
+}
+
+/// Cheap to clone handle sending commands to the thread owning a MAP port.
+/// The thread stops when the last handle is dropped.
+#[derive(Clone)]
+pub struct MapHandle {
+    commands: mpsc::Sender<MapCommand>,
+}
+
+impl MapHandle {
+    /// Opens the port and starts the thread serving it
+    pub fn spawn(name: String, mut open: Opener) -> anyhow::Result<Self> {
+        let protocol = open()?;
+        let (commands, mut receiver) = mpsc::channel(16);
+        let mut actor = MapActor {
+            protocol: Some(protocol),
+            open,
+            identity: None,
+        };
+        thread::Builder::new().name(name).spawn(move || {
+            while let Some(command) = receiver.blocking_recv() {
+                actor.handle(command);
+            }
+            debug!("map port closed");
+        })?;
+        Ok(Self { commands })
+    }
+
+    async fn call<T>(&self, command: MapCommand, reply: oneshot::Receiver<T>) -> anyhow::Result<T> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("map port thread stopped"))?;
+        reply.await.map_err(|_| anyhow!("map port thread stopped"))
+    }
+
+    /// Reads EEPROM, checks that it belongs to a MAP and detects the firmware
+    pub async fn identify(&self) -> anyhow::Result<Identity> {
+        let (reply, receiver) = oneshot::channel();
+        Ok(self.call(MapCommand::Identify(reply), receiver).await??)
+    }
+
+    /// Reads the status, identifying the MAP first if that did not happen yet
+    pub async fn read_status(&self) -> anyhow::Result<MapInfo> {
+        let (reply, receiver) = oneshot::channel();
+        Ok(self.call(MapCommand::ReadStatus(reply), receiver).await??)
+    }
+
+    pub async fn read_memory(&self, addr: u16, len: u16) -> anyhow::Result<Vec<u8>> {
+        let (reply, receiver) = oneshot::channel();
+        let command = MapCommand::ReadMemory { addr, len, reply };
+        Ok(self.call(command, receiver).await??)
+    }
+
+    /// Writes memory, refused on an unknown firmware or outside the EEPROM
+    /// limits of a setting unless `force` is given
+    pub async fn write_memory(&self, addr: u16, bytes: Vec<u8>, force: bool) -> anyhow::Result<()> {
+        let (reply, receiver) = oneshot::channel();
+        let command = MapCommand::WriteMemory {
+            addr,
+            bytes,
+            force,
+            reply,
+        };
+        self.call(command, receiver).await?
+    }
+
+    pub async fn stats(&self) -> anyhow::Result<ProtocolStats> {
+        let (reply, receiver) = oneshot::channel();
+        self.call(MapCommand::Stats(reply), receiver).await
+    }
+
+    /// Flushes and closes the port and opens it again
+    pub async fn reopen(&self) -> anyhow::Result<()> {
+        let (reply, receiver) = oneshot::channel();
+        self.call(MapCommand::Reopen(reply), receiver).await?
+    }
+}
+
+/// Whether the error is worth retrying the next poll instead of giving up
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<MapError>()
+        .is_some_and(MapError::is_transient)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::map_protocol::{RetryPolicy, Transport};
+
+    /// Port that counts its open handles, like a tty opened with TIOCEXCL
+    #[derive(Debug)]
+    struct ExclusivePort(Arc<AtomicUsize>);
+
+    impl Drop for ExclusivePort {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Read for ExclusivePort {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for ExclusivePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for ExclusivePort {
+        fn clear(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reopen_releases_the_old_port() {
+        let handles = Arc::new(AtomicUsize::new(0));
+        let opened = handles.clone();
+        let mut open: Opener = Box::new(move || {
+            if opened.fetch_add(1, Ordering::SeqCst) > 0 {
+                opened.fetch_sub(1, Ordering::SeqCst);
+                bail!("device busy");
+            }
+            let port = ExclusivePort(opened.clone());
+            Ok(HighLevelProtocol::new(
+                Box::new(port),
+                RetryPolicy::default(),
+                None,
+            )?)
+        });
+        let protocol = open().unwrap();
+        let mut actor = MapActor {
+            protocol: Some(protocol),
+            open,
+            identity: None,
+        };
+        for _ in 0..2 {
+            let (reply, mut receiver) = oneshot::channel();
+            actor.handle(MapCommand::Reopen(reply));
+            receiver.try_recv().unwrap().unwrap();
+            assert_eq!(handles.load(Ordering::SeqCst), 1);
+        }
+        drop(actor);
+        assert_eq!(handles.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,239 @@
+//! Frame level capture of the serial line.
+//!
+//! With `--capture FILE` every attempt of every command is appended to `FILE`
+//! as one JSON object per line:
+//!
+//! ```json
+//! {"ts":"2024-03-01T10:00:00.123456Z","port":"/dev/ttyUSB0","command":"ToRead","addr":1024,
+//!  "page":255,"attempt":0,"duration_us":35210,"sent":"72ff0400..","received":"72ff0400..",
+//!  "frame":"6f..0a","payload":"..","verdict":"ok"}
+//! ```
+//!
+//! * `ts` - RFC 3339 time the attempt started, `duration_us` - how long it took
+//! * `sent` - every byte written to the MAP: the command frame and the echo of the answer
+//! * `received` - every byte read: the echo of the command frame and the answer
+//! * `frame` - the raw answer, i.e. `received` after the echo of the command
+//! * `payload` - the answer with escaping, checksum and terminator removed, only when `verdict` is `ok`
+//! * `verdict` - `ok`, `checksum_failed`, `short_answer`, `bad_start_byte`, `echo_mismatch`,
+//!   `io_error` or `not_found`, with the error message in `error`
+//!
+//! Byte strings are lowercase hex. `capture show FILE` prints a capture in a readable form.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{low_level::Transport, MapError};
+use crate::hexdump::{hexdump, spaced};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Ok,
+    ChecksumFailed,
+    ShortAnswer,
+    BadStartByte,
+    EchoMismatch,
+    IoError,
+    NotFound,
+}
+
+impl From<&MapError> for Verdict {
+    fn from(error: &MapError) -> Self {
+        match error {
+            MapError::IOError { .. } => Verdict::IoError,
+            MapError::NotFound { .. } => Verdict::NotFound,
+            MapError::VerifyReadAfterWriteError { .. }
+            | MapError::VerifyReadAfterWriteRunawayError { .. }
+            | MapError::WriteError { .. } => Verdict::EchoMismatch,
+            MapError::FirstByteis65DontKnowWhatItMeans { .. }
+            | MapError::UnknownValueError { .. } => Verdict::BadStartByte,
+            MapError::ChecksumFailed { .. } => Verdict::ChecksumFailed,
+            MapError::ShortAnswer { .. } => Verdict::ShortAnswer,
+        }
+    }
+}
+
+/// One attempt of a command, a line of the capture file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub ts: String,
+    pub port: String,
+    pub command: String,
+    pub addr: u16,
+    pub page: u16,
+    pub attempt: u32,
+    pub duration_us: u64,
+    #[serde(with = "hex")]
+    pub sent: Vec<u8>,
+    #[serde(with = "hex")]
+    pub received: Vec<u8>,
+    #[serde(with = "hex")]
+    pub frame: Vec<u8>,
+    #[serde(with = "hex", default, skip_serializing_if = "Vec::is_empty")]
+    pub payload: Vec<u8>,
+    pub verdict: Verdict,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Bytes that went over the line during the current attempt
+#[derive(Debug, Default)]
+pub(crate) struct Wire {
+    pub sent: Vec<u8>,
+    pub received: Vec<u8>,
+}
+
+impl Wire {
+    /// The answer: what was read after the echo of the command terminator.
+    /// Escaping keeps `\n` out of the command, so its first echo is the terminator.
+    pub fn frame(&self) -> &[u8] {
+        match self.received.iter().position(|byte| *byte == b'\n') {
+            Some(end) => &self.received[end + 1..],
+            None => &[],
+        }
+    }
+}
+
+/// Transport passing everything through while copying it to a [`Wire`]
+#[derive(Debug)]
+pub(crate) struct Recording {
+    pub inner: Box<dyn Transport>,
+    pub wire: Arc<Mutex<Wire>>,
+}
+
+impl Read for Recording {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.wire.lock().unwrap().received.extend(&buf[..count]);
+        Ok(count)
+    }
+}
+
+impl Write for Recording {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.wire.lock().unwrap().sent.extend(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Recording {
+    fn clear(&mut self) -> io::Result<()> {
+        self.inner.clear()
+    }
+}
+
+/// Capture file opened for appending, so several ports and restarts can share one file
+#[derive(Debug)]
+pub struct CaptureFile {
+    file: File,
+    port: String,
+}
+
+impl CaptureFile {
+    pub fn open(path: &Path, port: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open capture file {}", path.display()))?;
+        Ok(Self {
+            file,
+            port: port.into(),
+        })
+    }
+
+    pub fn port(&self) -> &str {
+        &self.port
+    }
+
+    /// Appends the record as a single write, so lines of concurrent writers do not mix
+    pub fn write(&mut self, record: &CaptureRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+pub fn read(path: &Path) -> anyhow::Result<Vec<CaptureRecord>> {
+    let file =
+        File::open(path).with_context(|| format!("cannot open capture file {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(index, line)| {
+            serde_json::from_str(&line?)
+                .with_context(|| format!("{}:{}: bad capture record", path.display(), index + 1))
+        })
+        .collect()
+}
+
+/// Prints a capture, one block per attempt
+pub fn show(records: &[CaptureRecord], out: &mut impl Write) -> io::Result<()> {
+    for record in records {
+        write!(
+            out,
+            "{} {} {} {:#06x}/{:#04x}",
+            record.ts, record.port, record.command, record.addr, record.page
+        )?;
+        if record.attempt > 0 {
+            write!(out, " retry {}", record.attempt)?;
+        }
+        write!(
+            out,
+            " {:?} in {:.1}ms",
+            record.verdict,
+            record.duration_us as f64 / 1000.0
+        )?;
+        if let Some(error) = &record.error {
+            write!(out, ": {error}")?;
+        }
+        writeln!(out)?;
+        writeln!(out, "  sent     {}", spaced(&record.sent))?;
+        writeln!(out, "  received {}", spaced(&record.received))?;
+        writeln!(out, "  frame    {}", spaced(&record.frame))?;
+        if !record.payload.is_empty() {
+            writeln!(out, "  payload")?;
+            hexdump(record.addr, &record.payload, out)?;
+        }
+    }
+    Ok(())
+}
+
+mod hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let text: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if !text.len().is_multiple_of(2) {
+            return Err(serde::de::Error::custom("odd number of hex digits"));
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(serde::de::Error::custom)
+    }
+}
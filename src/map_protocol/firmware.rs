@@ -0,0 +1,281 @@
+//! Firmware detection and the quirks that depend on it.
+//!
+//! mapd carried "exception for earlier MAP FW releases" branches and read the
+//! 0x527 status block on every poll, falling back to `flag_eco = 255` when an
+//! older firmware did not answer. The bridge decides this once, when the MAP
+//! is identified, from the `hardware_version` and `firmware_version` bytes of
+//! the EEPROM image (built-in settings at 0x1F1 and 0x1F0, a `--register-map`
+//! can move them). A release listed in [`KNOWN_FIRMWARE`] selects its
+//! [`Quirks`], any other version is probed and its memory is not written:
+//!
+//! * [`Layout::Extended`] - the 0x527 block with phase currents, relays and
+//!   ECO flags answers
+//! * [`Layout::Basic`] - earlier firmware without the block, it is not polled
+//!   and `flag_eco` is 255. A MAP that does not answer at all fails the
+//!   identification
+//! * [`Layout::Unknown`] - an EEPROM setting holds a value outside the range
+//!   given by `valid` in the register map, so the layout cannot be trusted and
+//!   writes are refused
+
+use std::{fmt::Display, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+
+use super::registers::RegisterMap;
+
+/// Size of the EEPROM image read when a MAP is identified
+pub const EEPROM_SIZE: usize = 560;
+
+/// EEPROM settings that have their limits stored next to them
+const LIMITED_SETTINGS: RangeInclusive<usize> = 0x138..=0x1B7;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    Extended,
+    Basic,
+    #[default]
+    Unknown,
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::Extended => write!(f, "extended"),
+            Layout::Basic => write!(f, "basic"),
+            Layout::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// What differs between firmware releases
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// The status block at 0x527 with phase currents, relays and `flag_eco` answers
+    pub status_block: bool,
+    /// `Pmax_On` is stored at 0x58C, right after the status block
+    pub pmax: bool,
+}
+
+/// Firmware releases the layout is known of, by `firmware_version`
+pub const KNOWN_FIRMWARE: &[(RangeInclusive<u8>, Quirks)] = &[
+    // the earlier releases mapd had exceptions for
+    (
+        1..=19,
+        Quirks {
+            status_block: false,
+            pmax: false,
+        },
+    ),
+    (
+        20..=29,
+        Quirks {
+            status_block: true,
+            pmax: false,
+        },
+    ),
+    (
+        30..=99,
+        Quirks {
+            status_block: true,
+            pmax: true,
+        },
+    ),
+];
+
+/// What the bridge found out about the firmware of a MAP
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Firmware {
+    pub layout: Layout,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware_version: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<u8>,
+    /// `firmware_version` is in [`KNOWN_FIRMWARE`], writes are allowed
+    pub known: bool,
+    #[serde(skip)]
+    pub quirks: Quirks,
+}
+
+impl Display for Firmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} layout", self.layout)?;
+        if let Some(version) = self.hardware_version {
+            write!(f, ", hardware {version}")?;
+        }
+        match self.firmware_version {
+            Some(version) if self.known => write!(f, ", firmware {version}"),
+            Some(version) => write!(f, ", unknown firmware {version}"),
+            None => write!(f, ", no firmware version"),
+        }
+    }
+}
+
+/// Quirks of a release listed in [`KNOWN_FIRMWARE`]
+pub fn known_quirks(firmware_version: u8) -> Option<Quirks> {
+    KNOWN_FIRMWARE
+        .iter()
+        .find(|(versions, _)| versions.contains(&firmware_version))
+        .map(|(_, quirks)| *quirks)
+}
+
+/// Detects the firmware from the EEPROM image. `probe` tells whether the 0x527
+/// block answers, it is only called for a release missing from [`KNOWN_FIRMWARE`].
+pub fn detect<E>(
+    eeprom: &[u8],
+    registers: &RegisterMap,
+    probe: impl FnOnce() -> Result<bool, E>,
+) -> Result<Firmware, E> {
+    // an erased EEPROM byte reads 0xFF
+    let version = |name: &str| {
+        let register = registers.iter().find(|r| r.name == name && r.setting)?;
+        let value = register.decode(register.bytes_in(0, eeprom)?);
+        u8::try_from(value as i64)
+            .ok()
+            .filter(|value| *value != 0xFF)
+    };
+    let hardware_version = version("hardware_version");
+    let firmware_version = version("firmware_version");
+    let known = firmware_version.and_then(known_quirks);
+    let quirks = match known {
+        Some(quirks) => quirks,
+        // the block and Pmax come together on every release that has them
+        None => {
+            let status_block = probe()?;
+            Quirks {
+                status_block,
+                pmax: status_block,
+            }
+        }
+    };
+    let valid = registers
+        .iter()
+        .filter(|register| register.setting)
+        .all(|register| register.is_valid_in(0, eeprom));
+    let layout = match (valid, quirks.status_block) {
+        (false, _) => Layout::Unknown,
+        (true, true) => Layout::Extended,
+        (true, false) => Layout::Basic,
+    };
+    Ok(Firmware {
+        layout,
+        hardware_version,
+        firmware_version,
+        known: known.is_some(),
+        quirks,
+    })
+}
+
+/// Refuses writes that may damage the settings of a MAP.
+///
+/// Nothing is written on an [`Layout::Unknown`] layout or a firmware missing
+/// from [`KNOWN_FIRMWARE`]. EEPROM settings at
+/// 0x138..=0x1B7 have their limits stored 8 (minimum) and 16 (maximum) bytes
+/// after them, the same check mapd did before writing a setting.
+pub fn check_write(
+    firmware: &Firmware,
+    eeprom: &[u8],
+    addr: u16,
+    bytes: &[u8],
+) -> Result<(), String> {
+    if firmware.layout == Layout::Unknown {
+        return Err("the settings of this MAP do not match a known layout".into());
+    }
+    match firmware.firmware_version {
+        None => return Err("this MAP stores no firmware version".into()),
+        Some(version) if !firmware.known => {
+            return Err(format!("firmware version {version} is not a known release"))
+        }
+        Some(_) => {}
+    }
+    for (index, value) in bytes.iter().enumerate() {
+        let offset = addr as usize + index;
+        if !LIMITED_SETTINGS.contains(&offset) {
+            continue;
+        }
+        let (Some(min), Some(max)) = (eeprom.get(offset + 8), eeprom.get(offset + 16)) else {
+            continue;
+        };
+        if value < min || value > max {
+            return Err(format!(
+                "{value:#04x} at {offset:#06x} is outside the limits {min:#04x}..={max:#04x} stored in EEPROM"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(eeprom: &[u8], block_answers: bool) -> Firmware {
+        let probed = super::detect(eeprom, &RegisterMap::builtin(), || {
+            Ok::<_, ()>(block_answers)
+        });
+        probed.unwrap()
+    }
+
+    #[test]
+    fn selects_quirks_by_version() {
+        let mut eeprom = [0u8; EEPROM_SIZE];
+        eeprom[0x1F0] = 25;
+        eeprom[0x1F1] = 3;
+        let firmware = super::detect(&eeprom, &RegisterMap::builtin(), || -> Result<_, ()> {
+            panic!("a known release is not probed")
+        })
+        .unwrap();
+        assert_eq!(firmware.layout, Layout::Extended);
+        assert_eq!(
+            (firmware.firmware_version, firmware.hardware_version),
+            (Some(25), Some(3))
+        );
+        assert!(firmware.known);
+        assert!(!firmware.quirks.pmax);
+        assert_eq!(
+            firmware.to_string(),
+            "extended layout, hardware 3, firmware 25"
+        );
+
+        eeprom[0x1F0] = 10;
+        let earlier = detect(&eeprom, true);
+        assert_eq!(earlier.layout, Layout::Basic);
+        eeprom[0x1F0] = 30;
+        assert!(detect(&eeprom, false).quirks.pmax);
+    }
+
+    #[test]
+    fn probes_unknown_versions_and_refuses_writes() {
+        let mut eeprom = [0u8; EEPROM_SIZE];
+        eeprom[0x1F0] = 0xFF;
+        let erased = detect(&eeprom, true);
+        assert_eq!(erased.firmware_version, None);
+        assert_eq!(erased.layout, Layout::Extended);
+        assert!(erased.quirks.pmax);
+        assert!(check_write(&erased, &eeprom, 0x403, &[0]).is_err());
+
+        eeprom[0x1F0] = 200;
+        let unknown = detect(&eeprom, false);
+        assert_eq!(unknown.layout, Layout::Basic);
+        assert!(!unknown.known);
+        assert!(check_write(&unknown, &eeprom, 0x403, &[0]).is_err());
+    }
+
+    #[test]
+    fn detects_invalid_settings_and_checks_limits() {
+        let mut eeprom = [0u8; EEPROM_SIZE];
+        eeprom[0x1F0] = 30;
+        eeprom[0x13C] = 7; // net_up_eco is 0..=2
+        let invalid = detect(&eeprom, true);
+        assert_eq!(invalid.layout, Layout::Unknown);
+        assert!(check_write(&invalid, &eeprom, 0x403, &[0]).is_err());
+
+        eeprom[0x13C] = 0;
+        let firmware = detect(&eeprom, true);
+        eeprom[0x140 + 8] = 10;
+        eeprom[0x140 + 16] = 20;
+        assert!(check_write(&firmware, &eeprom, 0x140, &[15]).is_ok());
+        assert!(check_write(&firmware, &eeprom, 0x140, &[21]).is_err());
+        assert!(check_write(&firmware, &eeprom, 0x403, &[0xff]).is_ok());
+    }
+}
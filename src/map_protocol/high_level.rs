@@ -1,13 +1,16 @@
-use std::cmp::Ordering;
+use std::{collections::BTreeMap, io, sync::Arc};
 
-use enum_primitive_derive::Primitive;
-use num_traits::FromPrimitive;
 use serde::Serialize;
-use serialport::SerialPort;
+
+use snafu::{ensure, ResultExt};
 
 use super::{
-    low_level::{LowLevelCommands, LowLevelProtocol},
-    MapError,
+    capture::CaptureFile,
+    firmware::{self, Firmware, Layout, EEPROM_SIZE},
+    low_level::{LowLevelCommands, LowLevelProtocol, ProtocolStats, RetryPolicy, Transport},
+    mode::{real_mode, MapModeExtended},
+    registers::RegisterMap,
+    IOSnafu, MapError, NotFoundSnafu,
 };
 
 // pub struct BMSThreshold;
@@ -19,355 +22,542 @@ use super::{
 //     pub const BMS_LOW_T: u8 = 0;
 // }
 
-#[derive(Default, Debug, Serialize, PartialEq)]
+#[derive(Default, Debug, Clone, Serialize, PartialEq)]
 pub struct MapInfo {
-    mode: MapModeExtended,
-    status_char: u8,
-    u_acc: f32,
-    i_acc: u32,
-    p_load: u32,
-    f_acc_over: u8,
-    f_net_over: u8,
-    u_net: i32,
-    i_net: u8,
-    p_net: u32,
-    tf_net: u8,
-    th_f_map: u8,
-    u_ou_t_med: u32,
-    tf_net_limit: u8,
-    u_net_limit: u32,
-    rs_err_sis: u8,
-    rs_err_job_m: u8,
-    rs_err_job: u8,
-    rs_warning: u8,
-    temp_grad0: i8,
-    temp_grad1: i8,
-    temp_grad2: i8,
-    i_net_16_4: f32,
-    i_acc_med_a_u16: f32,
-    temp_off: u8,
-    e_net: u32,
-    e_acc: u32,
-    e_acc_charge: u32,
-    u_acc_optim: f32,
-    i_acc_avg: f32,
-    i_mppt_avg: f32,
-    i2_c_err: u8,
-    relay1: u8,
-    relay2: u8,
-    flag_eco: u8,
-    rs_err_dop: u8,
-    flag_u_net2: u8,
-    i_ph1: f32,
-    i_ph2: f32,
-    i_ph3: f32,
-    i_acc_3ph: f32,
+    /// Mode with the extensions resolved by [`real_mode`]
+    pub mode: MapModeExtended,
+    /// Mode as reported by the firmware, 0..=4
+    pub mode_raw: u8,
+    /// Human readable `mode`
+    pub mode_label: String,
+    pub status_char: u8,
+    pub u_acc: f32,
+    pub i_acc: u32,
+    pub p_load: u32,
+    pub f_acc_over: u8,
+    pub f_net_over: u8,
+    pub u_net: i32,
+    pub i_net: u8,
+    pub p_net: u32,
+    pub tf_net: u8,
+    pub th_f_map: u8,
+    pub u_ou_t_med: u32,
+    pub tf_net_limit: u8,
+    pub u_net_limit: u32,
+    pub rs_err_sis: u8,
+    pub rs_err_job_m: u8,
+    pub rs_err_job: u8,
+    pub rs_warning: u8,
+    pub temp_grad0: i8,
+    pub temp_grad1: i8,
+    pub temp_grad2: i8,
+    pub i_net_16_4: f32,
+    pub i_acc_med_a_u16: f32,
+    pub temp_off: u8,
+    pub e_net: u32,
+    pub e_acc: u32,
+    pub e_acc_charge: u32,
+    pub u_acc_optim: f32,
+    pub i_acc_avg: f32,
+    pub i_mppt_avg: f32,
+    pub i2_c_err: u8,
+    pub relay1: u8,
+    pub relay2: u8,
+    pub flag_eco: u8,
+    pub rs_err_dop: u8,
+    pub flag_u_net2: u8,
+    pub i_ph1: f32,
+    pub i_ph2: f32,
+    pub i_ph3: f32,
+    pub i_acc_3ph: f32,
+    /// Number of MAPs working together, from EEPROM 0x155
+    pub maps_count: u8,
+    /// Net battery current of the whole system in amperes, positive while charging
+    /// and negative while discharging, see [`battery_current`]
+    pub battery_current: f32,
+    /// Cells of the MAP BMS, empty when BMS is not enabled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bms: Vec<BmsCell>,
+    /// Detected when the MAP was identified
+    pub firmware: Firmware,
+    /// Registers of a `--register-map` file that are not fields of their own
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, f64>,
+}
+
+/// Cell monitored by the MAP BMS
+#[derive(Default, Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct BmsCell {
+    pub cell_number: u8,
+    /// Volts
+    pub v: f32,
+    /// Balancing current, amperes
+    pub i: f32,
+    /// Celsius, 127 when there is no sensor
+    pub t: i8,
+}
+
+impl MapInfo {
+    pub fn mode(&self) -> &MapModeExtended {
+        &self.mode
+    }
+
+    /// Error codes and flags that are not zero
+    pub fn errors(&self) -> Vec<(&'static str, u8)> {
+        [
+            ("rs_err_sis", self.rs_err_sis),
+            ("rs_err_job", self.rs_err_job),
+            ("rs_err_job_m", self.rs_err_job_m),
+            ("rs_err_dop", self.rs_err_dop),
+            ("rs_warning", self.rs_warning),
+            ("i2_c_err", self.i2_c_err),
+            ("f_acc_over", self.f_acc_over),
+            ("f_net_over", self.f_net_over),
+            ("temp_off", self.temp_off),
+        ]
+        .into_iter()
+        .filter(|(_, code)| *code != 0)
+        .collect()
+    }
+
+    /// Fields by name. Serialized through text, as `serde_json::to_value` would
+    /// widen `f32` values, e.g. 45.3 to 45.29999923706055
+    pub fn fields(&self) -> serde_json::Result<serde_json::Map<String, serde_json::Value>> {
+        serde_json::from_str(&serde_json::to_string(self)?)
+    }
+
+    /// Sets the field named after a register, values of registers without a field go to `extra`
+    pub fn set(&mut self, name: &str, value: f64) {
+        macro_rules! fields {
+            ($($field:ident),* $(,)?) => {
+                match name {
+                    $(stringify!($field) => self.$field = value as _,)*
+                    _ => {
+                        self.extra.insert(name.into(), value);
+                    }
+                }
+            };
+        }
+        fields!(
+            mode_raw,
+            status_char,
+            u_acc,
+            i_acc,
+            p_load,
+            f_acc_over,
+            f_net_over,
+            u_net,
+            i_net,
+            p_net,
+            tf_net,
+            th_f_map,
+            u_ou_t_med,
+            tf_net_limit,
+            u_net_limit,
+            rs_err_sis,
+            rs_err_job_m,
+            rs_err_job,
+            rs_warning,
+            temp_grad0,
+            temp_grad1,
+            temp_grad2,
+            i_net_16_4,
+            i_acc_med_a_u16,
+            temp_off,
+            e_net,
+            e_acc,
+            e_acc_charge,
+            u_acc_optim,
+            i_acc_avg,
+            i_mppt_avg,
+            i2_c_err,
+            relay1,
+            relay2,
+            flag_eco,
+            rs_err_dop,
+            flag_u_net2,
+            i_ph1,
+            i_ph2,
+            i_ph3,
+            i_acc_3ph,
+            maps_count,
+            battery_current,
+        );
+    }
+}
+
+/// Net battery current the way mapd fed it to the battery monitor.
+///
+/// `phase_config` is EEPROM 0x139:
+/// * 1..=3 - three-phase system, the phase currents read from 0x527 already sum
+///   up to the battery current with the right sign (`i_acc_3ph`)
+/// * 0 - single phase with `maps_count` MAPs in parallel on one battery, every
+///   MAP carries the same `i_acc_med` current
+/// * anything else - a single MAP
+///
+/// Outside of three-phase systems `i_acc_med` has no sign, so the current is
+/// taken as charging in [`MapModeExtended::PowerOnTranslatingExternalPowerAndCharging`]
+/// and as discharging in every other mode.
+pub fn battery_current(
+    phase_config: u8,
     maps_count: u8,
+    mode: &MapModeExtended,
+    i_acc_med: f32,
+    i_acc_3ph: f32,
+) -> f32 {
+    if (1..=3).contains(&phase_config) {
+        return i_acc_3ph;
+    }
+    let current = if *mode == MapModeExtended::PowerOnTranslatingExternalPowerAndCharging {
+        i_acc_med
+    } else {
+        -i_acc_med
+    };
+    if phase_config == 0 {
+        current * maps_count as f32
+    } else {
+        current
+    }
+}
+
+/// EEPROM image and firmware of a MAP, needed to decode its status
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub eeprom: [u8; EEPROM_SIZE],
+    pub firmware: Firmware,
+    /// Pmax setting at 0x58C, beyond the EEPROM image, 0 on firmware without it
+    pub pmax_on: u8,
 }
 
-#[derive(PartialEq, PartialOrd, Debug, Serialize, Primitive, Default)]
-#[repr(u8)]
-pub enum MapModeExtended {
-    /// МАП выключен и нет сети на входе
-    #[default]
-    PowerOff = 0,
-    /// МАП выключен но есть сеть на входе (значение напряжения сети выводится в ЖКИ)
-    PowerOffExternalPowerPresent = 1,
-    /// МАП включен (происходит генерация 220В от АКБ, нет сети на входе.
-    PowerOnGeneratingNoExternalPower = 2,
-    /// МАП включен и транслирует сеть (есть сеть на входе).
-    PowerOnTranslatingExternalPower = 3,
-    /// МАП включен, транслирует сеть и одновременно заряжает АКБ.
-    PowerOnTranslatingExternalPowerAndCharging = 4,
-
-    // ------------ my extensions------------------------
-    /// принудительная генерация
-    ForcedGeneration = 10,
-    /// тарифная сеть. максимальный тариф. принудительная генерация
-    SellingBackToGridMaxRateForcedGeneration = 11,
-    /// тарифная сеть. минимальный тариф
-    SellingBackToGridMinRate = 12,
-    /// трансляция + эко-подкачка
-    TranslationECOPumping = 13,
-    /// трансляция + продажа в сеть
-    TranslationSellingBackToGrid = 14,
-    /// ожидание внешнего заряда
-    WaitingForExternalCharge = 15,
-    /// тарифная сеть. трансляция+эко-подкачка
-    SellingBackToGridTranslationEcoPumping = 16,
-    /// тарифная сеть. трансляция+продажа в сеть
-    SellingBackToGridTranslation = 17,
-    /// режим подкачка Pmax
-    Pmax = 18,
+impl Identity {
+    /// Nominal battery voltage set at 0x006: 12, 24, 48 or 96 V
+    pub fn battery_voltage(&self) -> f32 {
+        f32::from(12u8 << self.eeprom[0x006].min(3))
+    }
 }
 
 #[derive(Debug)]
 pub struct HighLevelProtocol {
     low_level_protocol: LowLevelProtocol,
+    registers: Arc<RegisterMap>,
 }
 impl HighLevelProtocol {
-    pub fn new(port: Box<dyn SerialPort>) -> Result<Self, MapError> {
+    pub fn new(
+        port: Box<dyn Transport>,
+        retry_policy: RetryPolicy,
+        capture: Option<CaptureFile>,
+    ) -> Result<Self, MapError> {
         Ok(Self {
-            low_level_protocol: LowLevelProtocol::new(port),
+            low_level_protocol: LowLevelProtocol::new(port, retry_policy, capture),
+            registers: RegisterMap::builtin(),
+        })
+    }
+
+    /// Decodes the status with `registers` instead of the built-in register map
+    pub fn with_registers(mut self, registers: Arc<RegisterMap>) -> Self {
+        self.registers = registers;
+        self
+    }
+
+    pub fn stats(&self) -> ProtocolStats {
+        self.low_level_protocol.stats()
+    }
+
+    /// Reads EEPROM, checks that it belongs to a MAP and detects the firmware
+    pub fn identify(&mut self) -> Result<Identity, MapError> {
+        let eeprom = self.read_eeprom()?;
+        ensure!(eeprom[0] == 3, NotFoundSnafu);
+        // earlier firmware refuses the block with 0x65, any other error may be
+        // noise on the line and must not disable the block for good
+        let registers = self.registers.clone();
+        let firmware = firmware::detect(&eeprom, &registers, || {
+            match self.read_memory(0x527, 0x60) {
+                Ok(_) => Ok(true),
+                Err(MapError::FirstByteis65DontKnowWhatItMeans { .. }) => Ok(false),
+                Err(error) => Err(error),
+            }
+        })?;
+        let pmax_on = match firmware.quirks.pmax {
+            true => self.read_memory(0x58C, 1)?[0],
+            false => 0,
+        };
+        Ok(Identity {
+            eeprom,
+            firmware,
+            pmax_on,
         })
     }
 
-    pub fn read_eeprom(&mut self) -> Result<[u8; 560], MapError> {
-        let mut eeprom = [0u8; 560];
+    pub fn flush(&mut self) -> Result<(), MapError> {
+        self.low_level_protocol.flush()
+    }
+
+    pub fn read_eeprom(&mut self) -> Result<[u8; EEPROM_SIZE], MapError> {
+        let mut eeprom = [0u8; EEPROM_SIZE];
 
         self.read_eeprom_to_buffer(&mut eeprom)?;
         Ok(eeprom)
     }
 
-    pub fn read_eeprom_to_buffer(&mut self, eeprom: &mut [u8; 560]) -> Result<(), MapError> {
+    pub fn read_eeprom_to_buffer(
+        &mut self,
+        eeprom: &mut [u8; EEPROM_SIZE],
+    ) -> Result<(), MapError> {
         self.low_level_protocol
-            .send_command_clean_buffer(LowLevelCommands::ToRead, 0, 0xFF)?;
-        self.low_level_protocol.read_answer()?;
+            .request(LowLevelCommands::ToRead, 0, 0xFF)?;
 
         eeprom[0..self.low_level_protocol.last_read_bytes_index]
             .clone_from_slice(self.low_level_protocol.get_actually_read_slice());
 
         self.low_level_protocol
-            .send_command_clean_buffer(LowLevelCommands::ToRead, 0x100, 0xff)?;
-        self.low_level_protocol.read_answer()?;
+            .request(LowLevelCommands::ToRead, 0x100, 0xff)?;
 
         eeprom[0x100..(0x100 + self.low_level_protocol.last_read_bytes_index)]
             .clone_from_slice(self.low_level_protocol.get_actually_read_slice());
         Ok(())
     }
 
-    pub fn read_status(&mut self, eeprom: &[u8; 560]) -> Result<MapInfo, MapError> {
-        let mut map_info = MapInfo::default();
-        // let eeprom = self.read_eeprom()?;
-        self.low_level_protocol
-            .send_command_clean_buffer(LowLevelCommands::ToRead, 0x527, 0x5F)?;
-
-        let res = self.low_level_protocol.read_answer();
-        match res {
-            Ok(_) => {
-                let buffer = self.low_level_protocol.buffer;
-                map_info.flag_eco = buffer[0x5F];
-                map_info.relay1 = buffer[0x60] & 0b01;
-                map_info.relay2 = buffer[0x60] & 0b10;
-                map_info.flag_u_net2 = buffer[1];
-                //------------3 phase currents calculation---------------------
-                map_info.i_ph1 = (buffer[2] as f32 + ((buffer[3] & 0x7F) as f32) * 256.0) / 10.0;
-                map_info.i_ph2 = (buffer[4] as f32 + ((buffer[5] & 0x7F) as f32) * 256.0) / 10.0;
-                map_info.i_ph3 = (buffer[6] as f32 + ((buffer[7] & 0x7F) as f32) * 256.0) / 10.0;
-
-                map_info.i_ph1 = if buffer[3] & 0x80 == 0 {
-                    map_info.i_ph1
-                } else {
-                    0.0 - map_info.i_ph1
-                };
-                map_info.i_ph2 = if buffer[5] & 0x80 == 0 {
-                    map_info.i_ph2
-                } else {
-                    0.0 - map_info.i_ph2
-                };
-                map_info.i_ph3 = if buffer[7] & 0x80 == 0 {
-                    map_info.i_ph3
-                } else {
-                    0.0 - map_info.i_ph3
-                };
-                map_info.i_acc_3ph = map_info.i_ph1 + map_info.i_ph2 + map_info.i_ph3;
-            }
-            Err(_) => {
-                map_info.flag_eco = 255;
-            }
+    /// Reads `len` bytes starting at `addr`, in frames of up to 256 bytes
+    pub fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u8>, MapError> {
+        let mut memory = Vec::with_capacity(len as usize);
+        while memory.len() < len as usize {
+            let chunk = (len as usize - memory.len()).min(0x100);
+            self.low_level_protocol.request(
+                LowLevelCommands::ToRead,
+                offset(addr, memory.len())?,
+                chunk as u16 - 1,
+            )?;
+            // the low level checked that the whole chunk was answered
+            memory.extend_from_slice(&self.low_level_protocol.get_data_slice()[..chunk]);
         }
+        Ok(memory)
+    }
 
-        self.low_level_protocol
-            .send_command_clean_buffer(LowLevelCommands::ToRead, 0x400, 0xFF)?;
-
-        self.low_level_protocol.read_answer()?;
-
-        let buffer = self.low_level_protocol.buffer;
+    /// Writes `bytes` starting at `addr`, in frames of up to 256 bytes
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) -> Result<(), MapError> {
+        if let Some(last) = bytes.len().checked_sub(1) {
+            offset(addr, last)?;
+        }
+        for (index, chunk) in bytes.chunks(0x100).enumerate() {
+            self.low_level_protocol.buffer[..chunk.len()].copy_from_slice(chunk);
+            self.low_level_protocol.request(
+                LowLevelCommands::ToWrite,
+                offset(addr, index * 0x100)?,
+                chunk.len() as u16 - 1,
+            )?;
+        }
+        Ok(())
+    }
 
-        map_info.mode =
-            MapModeExtended::from_i32(buffer[0x400 - 0x3FF] as i32).expect("MapMode is unknown");
-        map_info.mode = self.real_mode(
-            map_info.mode,
-            eeprom[0x16B],
-            map_info.flag_eco,
-            eeprom[0x13C],
-            eeprom[0x13B],
-            map_info.u_net,
-            // eeprom[0x58C],
-        );
-        map_info.maps_count = if buffer[0x155] == 0xFF {
-            1
-        } else {
-            buffer[0x155]
+    pub fn read_status(&mut self, identity: &Identity) -> Result<MapInfo, MapError> {
+        let extended = match identity.firmware.layout {
+            Layout::Basic => None,
+            Layout::Extended | Layout::Unknown => self.read_memory(0x527, 0x60).ok(),
         };
+        let status = self.read_memory(0x400, 0x100)?;
+        Ok(decode_status(
+            &self.registers,
+            identity,
+            extended.as_deref(),
+            status,
+        ))
+    }
+}
 
-        map_info.u_net = buffer[0x422 - 0x3ff] as i32;
-        if map_info.u_net > 0 {
-            map_info.u_net += 100
+/// Status of a MAP from the memory read at 0x527, `None` when that failed, and at 0x400
+pub fn decode_status(
+    registers: &RegisterMap,
+    identity: &Identity,
+    extended: Option<&[u8]>,
+    mut buffer: Vec<u8>,
+) -> MapInfo {
+    let Identity {
+        eeprom, firmware, ..
+    } = identity;
+    let layout = firmware.layout;
+    let mut map_info = MapInfo {
+        firmware: firmware.clone(),
+        ..Default::default()
+    };
+    match extended {
+        Some(block) => {
+            for (register, value) in registers.decode(0x527, block, layout) {
+                map_info.set(&register.name, value);
+            }
+            map_info.i_acc_3ph = map_info.i_ph1 + map_info.i_ph2 + map_info.i_ph3;
         }
-
-        map_info.status_char = self.low_level_protocol.buffer[0x402 - 0x3ff];
-
-        map_info.u_acc = (self.low_level_protocol.buffer[0x405 - 0x3FF] as f32 * 256.0
-            + self.low_level_protocol.buffer[0x406 - 0x3FF] as f32)
-            / 10.0;
-
-        map_info.i_acc = self.low_level_protocol.buffer[0x408 - 0x3FF] as u32 * 2;
-
-        map_info.p_load = self.low_level_protocol.buffer[0x409 - 0x3FF] as u32 * 100;
-
-        map_info.f_acc_over = self.low_level_protocol.buffer[0x41C - 0x3FF];
-
-        map_info.f_net_over = self.low_level_protocol.buffer[0x41D - 0x3FF];
-
-        map_info.i_net = self.low_level_protocol.buffer[0x423 - 0x3FF];
-
-        map_info.p_net = self.low_level_protocol.buffer[0x424 - 0x3FF] as u32 * 100;
-
-        map_info.tf_net = self.low_level_protocol.buffer[0x425 - 0x3FF];
-        // закомментировано в оригинале map_info._TFNET = 6250 / map_info._TFNET;
-
-        map_info.th_f_map = self.low_level_protocol.buffer[0x426 - 0x3FF];
-        // закомментировано в оригинале map_info._ThFMAP = 6250 / map_info._ThFMAP;
-
-        map_info.u_ou_t_med = self.low_level_protocol.buffer[0x427 - 0x3FF] as u32;
-        if map_info.u_ou_t_med > 0 {
-            map_info.u_ou_t_med += 100;
+        None => {
+            map_info.flag_eco = 255;
         }
+    }
 
-        map_info.tf_net_limit = self.low_level_protocol.buffer[0x428 - 0x3FF];
-        // закомментировано в оригинале if (map_info._TFNET_Limit!=0) map_info._TFNET_Limit= 2500 / map_info._TFNET_Limit;
-
-        map_info.u_net_limit = self.low_level_protocol.buffer[0x429 - 0x3FF] as u32;
-        map_info.u_net_limit += 100;
+    buffer.resize(0x100, 0);
+    for (register, value) in registers.decode(0x400, &buffer, layout) {
+        map_info.set(&register.name, value);
+    }
 
-        map_info.rs_err_sis = self.low_level_protocol.buffer[0x42A - 0x3FF];
-        map_info.rs_err_job_m = self.low_level_protocol.buffer[0x42B - 0x3FF];
+    // every value the mode depends on is decoded by now
+    map_info.mode = real_mode(
+        map_info.mode_raw.into(),
+        eeprom[0x16B],
+        map_info.flag_eco,
+        eeprom[0x13C],
+        eeprom[0x13B],
+        map_info.u_net,
+        identity.pmax_on,
+    );
+    map_info.mode_label = map_info.mode.label().into();
+    map_info.maps_count = if eeprom[0x155] == 0xFF {
+        1
+    } else {
+        eeprom[0x155].saturating_add(1)
+    };
+
+    // the precise grid current has a coarser scale above 16 A
+    if map_info.i_net >= 16 {
+        map_info.i_net_16_4 *= 4.0;
+    }
 
-        map_info.rs_err_job = self.low_level_protocol.buffer[0x42C - 0x3FF];
+    map_info.battery_current = battery_current(
+        eeprom[0x139],
+        map_info.maps_count,
+        &map_info.mode,
+        map_info.i_acc_med_a_u16,
+        map_info.i_acc_3ph,
+    );
+
+    //----------------- adding BMS data --------------------------------
+    if eeprom[0x156] == 3 || eeprom[0x156] == 1 {
+        // number of memory cells to read, two per battery cell
+        let limit = 1usize << (eeprom[0x06].min(3) + 3);
+        map_info.bms = (0..limit)
+            .step_by(2)
+            .enumerate()
+            .map(|(cell, i)| {
+                let v = (buffer[0x80 + i] as f32 + (buffer[0x80 + i + 1] & 0x7F) as f32 * 256.0)
+                    / 100.0;
+                BmsCell {
+                    cell_number: cell as u8 + 1,
+                    v,
+                    i: buffer[0xE0 + cell] as f32 * v / 100.0,
+                    t: if buffer[0xC0 + cell] == 255 {
+                        127
+                    } else {
+                        (buffer[0xC0 + cell] as i16 - 50) as i8
+                    },
+                }
+            })
+            .collect();
+    }
 
-        map_info.rs_warning = self.low_level_protocol.buffer[0x2E];
+    // //---------------------------Checking EEPROM change-------------------------
+
+    // if (self.low_level_protocol.buffer[0x04] & 5 > 0) {
+    //     if (self.read_eeprom_to_buffer(eeprom, fd, mysql) == 0) {
+    //         self.low_level_protocol.buffer[0] = 3;
+    //         send_command(to_write, fd, 0x0, 0x0);
+    //         if (read_answer(fd) == 0) {
+    //             self.low_level_protocol.buffer[0] = 0;
+    //             send_command(to_write, fd, 0x403, 0x0);
+    //             read_answer(fd);
+    //         }
+    //     }
+    // }
+
+    map_info
+}
 
-        map_info.temp_grad0 = self.low_level_protocol.buffer[0x2F] as i8 - 50;
-        map_info.temp_grad1 = self.low_level_protocol.buffer[0x30] as i8 - 50;
+/// Address `distance` bytes after `addr`, an error past the end of the MAP memory
+fn offset(addr: u16, distance: usize) -> Result<u16, MapError> {
+    u16::try_from(distance)
+        .ok()
+        .and_then(|distance| addr.checked_add(distance))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{addr:#06x} + {distance:#x} is past the end of the MAP memory"),
+            )
+        })
+        .context(IOSnafu)
+}
 
-        map_info.temp_grad2 = self.low_level_protocol.buffer[0x430 - 0x3FF] as i8 - 50;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_is_resolved_with_decoded_grid_voltage() {
+        let mut identity = Identity {
+            eeprom: [0; EEPROM_SIZE],
+            firmware: Firmware {
+                layout: Layout::Extended,
+                ..Default::default()
+            },
+            pmax_on: 0,
+        };
+        identity.eeprom[0x16B] = 2; // net_alg ECO
+        let mut status = vec![0; 0x100];
+        status[0] = MapModeExtended::PowerOnGeneratingNoExternalPower.code();
+        status[0x22] = 130; // u_net 230 V
+        let registers = RegisterMap::builtin();
+        let map_info = decode_status(&registers, &identity, Some(&[0; 0x60]), status.clone());
+        assert_eq!(map_info.u_net, 230);
+        assert_eq!(map_info.mode_raw, 2);
+        assert_eq!(map_info.mode, MapModeExtended::ForcedGeneration);
+        assert_eq!(map_info.mode_label, "Forced generation");
+
+        // without grid the MAP just generates
+        status[0x22] = 0;
+        let map_info = decode_status(&registers, &identity, None, status);
+        assert_eq!(map_info.flag_eco, 255);
+        assert_eq!(
+            map_info.mode,
+            MapModeExtended::PowerOnGeneratingNoExternalPower
+        );
+    }
 
-        if map_info.i_net < 16 {
-            map_info.i_net_16_4 = self.low_level_protocol.buffer[0x32] as f32 / 16.0;
-        } else {
-            map_info.i_net_16_4 = self.low_level_protocol.buffer[0x32] as f32 / 4.0;
-        }
+    #[test]
+    fn offsets_stay_within_the_memory() {
+        assert_eq!(offset(0x400, 0x100).unwrap(), 0x500);
+        assert_eq!(offset(0xFF00, 0xFF).unwrap(), 0xFFFF);
+        assert!(offset(0xFF00, 0x100).is_err());
+        assert!(offset(0, 0x10000).is_err());
+    }
 
-        map_info.i_acc_med_a_u16 = self.low_level_protocol.buffer[0x34] as f32 * 16.0
-            + self.low_level_protocol.buffer[0x33] as f32 / 16.0;
-
-        map_info.temp_off = self.low_level_protocol.buffer[0x43C - 0x3FF];
-        map_info.e_net = self.low_level_protocol.buffer[0x50] as u32 * 65536
-            + self.low_level_protocol.buffer[0x4F] as u32 * 256
-            + self.low_level_protocol.buffer[0x4E] as u32;
-        map_info.e_acc = self.low_level_protocol.buffer[0x53] as u32 * 65536
-            + self.low_level_protocol.buffer[0x52] as u32 * 256
-            + self.low_level_protocol.buffer[0x51] as u32;
-        map_info.e_acc_charge = self.low_level_protocol.buffer[0x56] as u32 * 65536
-            + self.low_level_protocol.buffer[0x55] as u32 * 256
-            + self.low_level_protocol.buffer[0x54] as u32;
-        map_info.i2_c_err = self.low_level_protocol.buffer[0x45A - 0x3FF];
-        map_info.rs_err_dop = self.low_level_protocol.buffer[0x447 - 0x3FF];
-
-        // //---------------------------Checking EEPROM change-------------------------
-
-        // if (self.low_level_protocol.buffer[0x04] & 5 > 0) {
-        //     if (self.read_eeprom_to_buffer(eeprom, fd, mysql) == 0) {
-        //         self.low_level_protocol.buffer[0] = 3;
-        //         send_command(to_write, fd, 0x0, 0x0);
-        //         if (read_answer(fd) == 0) {
-        //             self.low_level_protocol.buffer[0] = 0;
-        //             send_command(to_write, fd, 0x403, 0x0);
-        //             read_answer(fd);
-        //         }
-        //     }
-        // }
-
-        Ok(map_info)
+    #[test]
+    fn battery_current_single_map() {
+        let charging = MapModeExtended::PowerOnTranslatingExternalPowerAndCharging;
+        let generating = MapModeExtended::PowerOnGeneratingNoExternalPower;
+        assert_eq!(battery_current(0xFF, 1, &charging, 12.5, 0.0), 12.5);
+        assert_eq!(battery_current(0xFF, 1, &generating, 12.5, 0.0), -12.5);
+        assert_eq!(
+            battery_current(4, 1, &MapModeExtended::ForcedGeneration, 3.0, 1.0),
+            -3.0
+        );
     }
 
-    fn real_mode(
-        &self,
-        mode: MapModeExtended,
-        net_alg: u8,
-        flag_eco: u8,
-        net_up_eco: u8,
-        net_up_load: u8,
-        unet: i32,
-        // Pmax_On: u8,
-    ) -> MapModeExtended {
-        if mode != MapModeExtended::PowerOnGeneratingNoExternalPower
-            || mode != MapModeExtended::PowerOnTranslatingExternalPower
-        {
-            return mode;
-        }
+    #[test]
+    fn battery_current_parallel_maps() {
+        let charging = MapModeExtended::PowerOnTranslatingExternalPowerAndCharging;
+        let translating = MapModeExtended::PowerOnTranslatingExternalPower;
+        assert_eq!(battery_current(0, 1, &charging, 10.0, 0.0), 10.0);
+        assert_eq!(battery_current(0, 2, &charging, 10.0, 0.0), 20.0);
+        assert_eq!(battery_current(0, 3, &translating, 10.0, 0.0), -30.0);
+    }
 
-        if net_up_eco == 0 {
-            // ECO forced gen or Tarifs
-            // if NetUpLoad == 1 && (Pmax_On & 2 > 0) && (UNET > 100) {
-            //     return MapModeExtended::Pmax;
-            // }
-            if mode == MapModeExtended::PowerOnGeneratingNoExternalPower {
-                if unet > 100 {
-                    if net_alg == 2 {
-                        return MapModeExtended::ForcedGeneration;
-                    } else if net_alg == 3 {
-                        if (flag_eco & 2) == 0 {
-                            return MapModeExtended::SellingBackToGridMaxRateForcedGeneration;
-                        } else {
-                            return MapModeExtended::SellingBackToGridMinRate;
-                        }
-                    }
-                }
-                MapModeExtended::PowerOnGeneratingNoExternalPower
-            } else {
-                mode
-            }
-        } else if net_up_eco == 1 {
-            // Eco pumping
-
-            if mode == MapModeExtended::PowerOnTranslatingExternalPower {
-                if net_alg == 2 {
-                    return match (flag_eco & 1).cmp(&0u8) {
-                        Ordering::Greater => MapModeExtended::WaitingForExternalCharge,
-                        Ordering::Equal => MapModeExtended::TranslationECOPumping,
-                        Ordering::Less => mode,
-                    };
-                } else if net_alg == 3 {
-                    return match (flag_eco & 2).cmp(&0u8) {
-                        Ordering::Greater => MapModeExtended::SellingBackToGridMinRate,
-                        Ordering::Equal => MapModeExtended::SellingBackToGridTranslationEcoPumping,
-                        Ordering::Less => mode,
-                    };
-                } else {
-                    return mode;
-                }
-            } else {
-                return mode;
-            }
-        } else if net_up_eco == 2 {
-            // Sell to network
-            if mode == MapModeExtended::PowerOnTranslatingExternalPower {
-                if net_alg == 2 {
-                    if flag_eco & 1 > 0 {
-                        return MapModeExtended::WaitingForExternalCharge;
-                    } else {
-                        return MapModeExtended::TranslationSellingBackToGrid;
-                    }
-                } else {
-                    return mode;
-                }
-            } else {
-                return mode;
-            }
-        } else {
-            return mode;
+    #[test]
+    fn battery_current_three_phase() {
+        let charging = MapModeExtended::PowerOnTranslatingExternalPowerAndCharging;
+        let generating = MapModeExtended::PowerOnGeneratingNoExternalPower;
+        for phase_config in 1..=3 {
+            assert_eq!(
+                battery_current(phase_config, 3, &charging, 10.0, 27.5),
+                27.5
+            );
+            assert_eq!(
+                battery_current(phase_config, 3, &generating, 10.0, -31.2),
+                -31.2
+            );
         }
     }
 }
@@ -1,12 +1,21 @@
 use std::{
-    fmt::Display,
-    io::{Read, Write},
+    fmt::{Debug, Display},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use serialport::SerialPort;
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use serde::Serialize;
+use serialport::{ClearBuffer, SerialPort};
 use snafu::ResultExt;
 
-use super::{IOSnafu, MapError};
+use super::{
+    capture::{self, CaptureFile, CaptureRecord, Recording, Verdict, Wire},
+    IOSnafu, MapError,
+};
 
 const BUFFER_SIZE: u16 = 560;
 
@@ -29,32 +38,233 @@ impl Display for LowLevelCommands {
         }
     }
 }
+/// Byte stream the protocol talks over, a serial port to the MAP in production
+pub trait Transport: Read + Write + Send + Debug {
+    /// Drops everything pending in the input and output buffers
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn clear(&mut self) -> io::Result<()> {
+        (**self).clear(ClearBuffer::All).map_err(io::Error::from)
+    }
+}
+
+/// Log an error every this many consecutive failed attempts, as mapd did
+const FAILURES_TO_REPORT: u32 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a failed command is repeated before the error is returned
+    pub retries: u32,
+    /// Pause before every command frame sent to the MAP
+    pub inter_frame_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            inter_frame_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Counters of serial exchanges with the MAP
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProtocolStats {
+    /// Commands that eventually got a valid answer
+    pub succeeded: u64,
+    /// Commands that failed even after all retries
+    pub failed: u64,
+    /// Attempts repeated after a transient error
+    pub retries: u64,
+    /// Failed attempts since the last valid answer
+    pub consecutive_failures: u32,
+}
+
 #[derive(Debug)]
 pub struct LowLevelProtocol {
-    port: Box<dyn SerialPort>,
+    port: Box<dyn Transport>,
     sum: u8,
+    retry_policy: RetryPolicy,
+    stats: ProtocolStats,
+    /// Where attempts are recorded and the bytes of the current one
+    capture: Option<(CaptureFile, Arc<Mutex<Wire>>)>,
     pub buffer: [u8; BUFFER_SIZE as usize],
     pub last_read_bytes_index: usize,
 }
 impl LowLevelProtocol {
-    pub fn new(port: Box<dyn SerialPort>) -> Self {
+    pub fn new(
+        port: Box<dyn Transport>,
+        retry_policy: RetryPolicy,
+        capture: Option<CaptureFile>,
+    ) -> Self {
+        let (port, capture) = match capture {
+            Some(file) => {
+                let wire = Arc::new(Mutex::new(Wire::default()));
+                let port: Box<dyn Transport> = Box::new(Recording {
+                    inner: port,
+                    wire: wire.clone(),
+                });
+                (port, Some((file, wire)))
+            }
+            None => (port, None),
+        };
         Self {
             port,
             sum: 0,
+            retry_policy,
+            stats: ProtocolStats::default(),
+            capture,
             buffer: [0; BUFFER_SIZE as usize],
             last_read_bytes_index: 0,
         }
     }
 
+    pub fn stats(&self) -> ProtocolStats {
+        self.stats
+    }
+
+    /// Sends a command and reads the answer, flushing the port and repeating
+    /// the whole exchange on transient errors.
+    ///
+    /// For [`LowLevelCommands::ToWrite`] the data to write has to be put in
+    /// `buffer` beforehand and is preserved between attempts.
+    pub fn request(
+        &mut self,
+        command: LowLevelCommands,
+        addr: u16,
+        page: u16,
+    ) -> Result<(), MapError> {
+        let payload = self.buffer;
+        let mut attempt = 0;
+        loop {
+            if !self.retry_policy.inter_frame_delay.is_zero() {
+                thread::sleep(self.retry_policy.inter_frame_delay);
+            }
+            let started = (Utc::now(), Instant::now());
+            let result = if command == LowLevelCommands::ToRead {
+                self.send_command_clean_buffer(command, addr, page)
+            } else {
+                // the answer to a failed attempt overwrites the data to write
+                self.buffer = payload;
+                self.send_command(command, addr, page)
+            }
+            .and_then(|_| self.read_answer())
+            .and_then(|()| self.check_length(command, page));
+            self.record(command, addr, page, attempt, started, &result);
+
+            let error = match result {
+                Ok(()) => {
+                    self.stats.succeeded += 1;
+                    self.stats.consecutive_failures = 0;
+                    return Ok(());
+                }
+                Err(error) => error,
+            };
+            self.stats.consecutive_failures += 1;
+            if self
+                .stats
+                .consecutive_failures
+                .is_multiple_of(FAILURES_TO_REPORT)
+            {
+                error!(
+                    "{} consecutive MAP read errors, last one: {}",
+                    self.stats.consecutive_failures, error
+                );
+            }
+            if !error.is_transient() || attempt >= self.retry_policy.retries {
+                self.stats.failed += 1;
+                return Err(error);
+            }
+            attempt += 1;
+            self.stats.retries += 1;
+            debug!(
+                "{} {:#x}/{:#x} failed: {}, retry {} of {}",
+                command, addr, page, error, attempt, self.retry_policy.retries
+            );
+            if let Err(flush_error) = self.flush() {
+                warn!("cannot flush MAP port: {}", flush_error);
+            }
+        }
+    }
+
+    fn record(
+        &mut self,
+        command: LowLevelCommands,
+        addr: u16,
+        page: u16,
+        attempt: u32,
+        (started_at, started): (DateTime<Utc>, Instant),
+        result: &Result<(), MapError>,
+    ) {
+        if self.capture.is_none() {
+            return;
+        }
+        let (verdict, error, payload) = match result {
+            Ok(()) => (Verdict::Ok, None, self.get_data_slice().to_vec()),
+            Err(error) => (Verdict::from(error), Some(error.to_string()), Vec::new()),
+        };
+        let Some((file, wire)) = &mut self.capture else {
+            return;
+        };
+        let wire = std::mem::take(&mut *wire.lock().unwrap());
+        let record = CaptureRecord {
+            ts: capture::timestamp(started_at),
+            port: file.port().into(),
+            command: command.to_string(),
+            addr,
+            page,
+            attempt,
+            duration_us: started.elapsed().as_micros() as u64,
+            frame: wire.frame().to_vec(),
+            sent: wire.sent,
+            received: wire.received,
+            payload,
+            verdict,
+            error,
+        };
+        if let Err(error) = file.write(&record) {
+            warn!("cannot write capture: {}", error);
+        }
+    }
+
     pub fn clear_buffer(&mut self) {
         self.buffer.fill(0);
         self.last_read_bytes_index = 0;
     }
 
+    /// Drops everything pending in the port input and output buffers, like `tcflush(fd, TCIOFLUSH)`
+    pub fn flush(&mut self) -> Result<(), MapError> {
+        self.port.clear().context(IOSnafu)
+    }
+
     pub fn get_actually_read_slice(&self) -> &[u8] {
         &self.buffer[1..=self.last_read_bytes_index]
     }
 
+    /// Data of the last answer, without the start byte, the checksum and `\n`
+    pub fn get_data_slice(&self) -> &[u8] {
+        self.buffer
+            .get(1..self.last_read_bytes_index.saturating_sub(1))
+            .unwrap_or_default()
+    }
+
+    /// A read must answer all `page + 1` bytes asked for
+    fn check_length(&self, command: LowLevelCommands, page: u16) -> Result<(), MapError> {
+        let expected = usize::from(page) + 1;
+        let count = self.get_data_slice().len();
+        if command == LowLevelCommands::ToRead && count < expected {
+            return Err(MapError::ShortAnswer {
+                count,
+                expected,
+                backtrace: std::backtrace::Backtrace::capture(),
+            });
+        }
+        Ok(())
+    }
+
     fn put_char(&mut self, c: u8) -> Result<(), MapError> {
         let mut verify_buffer: [u8; 1] = [0; 1];
         let mut counter: u8 = 0;
@@ -244,3 +454,286 @@ impl LowLevelProtocol {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::map_protocol::replay::Replay;
+
+    /// Plays the MAP side of the line: echoes every command byte and answers
+    /// each command with the next queued frame
+    #[derive(Debug, Default)]
+    struct MockMap {
+        answers: VecDeque<Vec<u8>>,
+        input: VecDeque<u8>,
+        sent: Vec<u8>,
+        answering: bool,
+        /// How many times the buffers were cleared
+        clears: Arc<AtomicUsize>,
+    }
+
+    impl Read for MockMap {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    impl Write for MockMap {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                self.sent.push(byte);
+                if self.answering {
+                    // the host echoes the answer, the last echo is the frame end
+                    self.answering = byte != b'\n';
+                } else {
+                    self.input.push_back(byte);
+                    if byte == b'\n' {
+                        self.answering = true;
+                        self.input
+                            .extend(self.answers.pop_front().unwrap_or_default());
+                    }
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MockMap {
+        fn clear(&mut self) -> io::Result<()> {
+            self.input.clear();
+            self.answering = false;
+            self.clears.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Answer frame as the MAP sends it: escaped payload, checksum and `\n`
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x6f];
+        for &byte in data {
+            match byte {
+                b'\n' => frame.extend([0xDB, 0xDC]),
+                0xDB => frame.extend([0xDB, 0xDD]),
+                byte => frame.push(byte),
+            }
+        }
+        let sum = frame.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let checksum = (0xFF - sum).wrapping_add(1);
+        assert_ne!(checksum, b'\n', "pick other test data");
+        frame.extend([checksum, b'\n']);
+        frame
+    }
+
+    fn protocol(answers: Vec<Vec<u8>>) -> LowLevelProtocol {
+        let map = MockMap {
+            answers: answers.into(),
+            ..Default::default()
+        };
+        LowLevelProtocol::new(Box::new(map), RetryPolicy::default(), None)
+    }
+
+    #[test]
+    fn reads_and_unescapes_answer() {
+        let data = [0x01, b'\n', 0xDB, 0x02];
+        let mut protocol = protocol(vec![frame(&data)]);
+        protocol
+            .request(LowLevelCommands::ToRead, 0x400, 3)
+            .unwrap();
+        assert_eq!(&protocol.get_actually_read_slice()[..4], &data);
+        assert_eq!(protocol.stats().succeeded, 1);
+    }
+
+    #[test]
+    fn retries_after_checksum_error() {
+        let data = [0x11, 0x22, 0x33];
+        let mut corrupted = frame(&data);
+        corrupted[2] ^= 0xFF;
+        let mut protocol = protocol(vec![corrupted, frame(&data)]);
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+        assert_eq!(&protocol.get_actually_read_slice()[..3], &data);
+        let stats = protocol.stats();
+        assert_eq!((stats.succeeded, stats.retries, stats.failed), (1, 1, 0));
+    }
+
+    #[test]
+    fn retries_short_answers() {
+        let data = [0x11, 0x22, 0x33, 0x44];
+        let mut retried = protocol(vec![frame(&data[..2]), frame(&data)]);
+        retried.request(LowLevelCommands::ToRead, 0x527, 3).unwrap();
+        assert_eq!(retried.get_data_slice(), &data);
+        assert_eq!(retried.stats().retries, 1);
+
+        let mut short = protocol(vec![frame(&data[..2]); 4]);
+        let error = short
+            .request(LowLevelCommands::ToRead, 0x527, 3)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            MapError::ShortAnswer {
+                count: 2,
+                expected: 4,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let mut protocol = protocol(vec![]);
+        let error = protocol
+            .request(LowLevelCommands::ToRead, 0, 0xFF)
+            .unwrap_err();
+        assert!(error.is_transient());
+        let stats = protocol.stats();
+        assert_eq!((stats.failed, stats.retries), (1, 3));
+    }
+
+    #[test]
+    fn flushes_leftovers_before_retry() {
+        let data = [0x11, 0x22, 0x33];
+        // a corrupted answer followed by noise that would break the next echo
+        let mut corrupted = frame(&data);
+        corrupted[2] ^= 0xFF;
+        corrupted.extend([0x55, 0xAA]);
+        let clears = Arc::new(AtomicUsize::new(0));
+        let map = MockMap {
+            answers: vec![corrupted, frame(&data)].into(),
+            clears: clears.clone(),
+            ..Default::default()
+        };
+        let mut protocol = LowLevelProtocol::new(Box::new(map), RetryPolicy::default(), None);
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+        assert_eq!(&protocol.get_actually_read_slice()[..3], &data);
+        assert_eq!(clears.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn follows_retry_policy() {
+        let data = [0x11, 0x22, 0x33];
+        let mut corrupted = frame(&data);
+        corrupted[2] ^= 0xFF;
+        let map = MockMap {
+            answers: vec![corrupted, frame(&data)].into(),
+            ..Default::default()
+        };
+        let policy = RetryPolicy {
+            retries: 0,
+            inter_frame_delay: Duration::from_millis(5),
+        };
+        let mut protocol = LowLevelProtocol::new(Box::new(map), policy, None);
+        let started = Instant::now();
+        assert!(protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .is_err());
+        assert!(started.elapsed() >= policy.inter_frame_delay);
+        let stats = protocol.stats();
+        assert_eq!((stats.failed, stats.retries), (1, 0));
+        assert_eq!(stats.consecutive_failures, 1);
+
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+        let stats = protocol.stats();
+        assert_eq!((stats.succeeded, stats.consecutive_failures), (1, 0));
+    }
+
+    #[test]
+    fn captures_every_attempt() {
+        let path = std::env::temp_dir().join(format!("map-capture-{}.jsonl", std::process::id()));
+        let data = [0x11, b'\n', 0x33];
+        let mut corrupted = frame(&data);
+        corrupted[2] ^= 0xFF;
+        let map = MockMap {
+            answers: vec![corrupted.clone(), frame(&data)].into(),
+            ..Default::default()
+        };
+        let capture = CaptureFile::open(&path, "mock").unwrap();
+        let mut protocol =
+            LowLevelProtocol::new(Box::new(map), RetryPolicy::default(), Some(capture));
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+
+        let records = capture::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].verdict, Verdict::ChecksumFailed);
+        assert_eq!(records[0].frame, corrupted);
+        assert!(records[0].payload.is_empty());
+        assert_eq!(records[1].verdict, Verdict::Ok);
+        assert_eq!(records[1].attempt, 1);
+        assert_eq!(records[1].frame, frame(&data));
+        assert_eq!(records[1].payload, data);
+        assert_eq!(&records[1].sent[..4], &[0x72, 2, 0x05, 0x27]);
+    }
+
+    /// Session recorded from the mock, with a retried checksum error
+    fn record_session(path: &std::path::Path, data: &[u8]) {
+        let mut corrupted = frame(data);
+        corrupted[2] ^= 0xFF;
+        let map = MockMap {
+            answers: vec![corrupted, frame(data), frame(data)].into(),
+            ..Default::default()
+        };
+        let capture = CaptureFile::open(path, "mock").unwrap();
+        let mut protocol =
+            LowLevelProtocol::new(Box::new(map), RetryPolicy::default(), Some(capture));
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+        protocol
+            .request(LowLevelCommands::ToRead, 0x400, 2)
+            .unwrap();
+    }
+
+    #[test]
+    fn replays_captured_session() {
+        let path = std::env::temp_dir().join(format!("map-replay-{}.jsonl", std::process::id()));
+        let data = [0x11, 0xDB, 0x33];
+        record_session(&path, &data);
+        let records = capture::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let replay = Replay::new(records.clone());
+        let mut protocol = LowLevelProtocol::new(Box::new(replay), RetryPolicy::default(), None);
+        protocol
+            .request(LowLevelCommands::ToRead, 0x527, 2)
+            .unwrap();
+        assert_eq!(&protocol.get_actually_read_slice()[..3], &data);
+        assert_eq!(protocol.stats().retries, 1);
+        protocol
+            .request(LowLevelCommands::ToRead, 0x400, 2)
+            .unwrap();
+        assert_eq!(&protocol.get_actually_read_slice()[..3], &data);
+        let error = protocol
+            .request(LowLevelCommands::ToRead, 0x400, 2)
+            .unwrap_err();
+        assert!(!error.is_transient(), "{error}");
+
+        let replay = Replay::new(records);
+        let mut protocol = LowLevelProtocol::new(Box::new(replay), RetryPolicy::default(), None);
+        let error = protocol
+            .request(LowLevelCommands::ToRead, 0x400, 2)
+            .unwrap_err();
+        assert!(!error.is_transient(), "{error}");
+    }
+}
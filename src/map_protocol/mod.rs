@@ -1,7 +1,14 @@
 use snafu::{Backtrace, Snafu};
 
+pub mod capture;
+pub mod firmware;
 pub mod high_level;
 mod low_level;
+pub mod mode;
+pub mod registers;
+pub mod replay;
+
+pub use low_level::{ProtocolStats, RetryPolicy, Transport};
 
 #[derive(Snafu, Debug)]
 #[snafu(visibility(pub(crate)))]
@@ -25,4 +32,34 @@ pub enum MapError {
     UnknownValueError { value: u8, backtrace: Backtrace },
     #[snafu(display("MAP read error, checksum failed {value}"))]
     ChecksumFailed { value: u8, backtrace: Backtrace },
+    #[snafu(display("MAP read error, {count} bytes answered, {expected} expected"))]
+    ShortAnswer {
+        count: usize,
+        expected: usize,
+        backtrace: Backtrace,
+    },
+}
+
+impl MapError {
+    /// Errors caused by noise or a lost byte on the line, worth repeating the command for
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MapError::IOError { source, .. } => matches!(
+                source.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            MapError::NotFound { .. } => false,
+            MapError::VerifyReadAfterWriteError { .. }
+            | MapError::VerifyReadAfterWriteRunawayError { .. }
+            | MapError::WriteError { .. }
+            | MapError::FirstByteis65DontKnowWhatItMeans { .. }
+            | MapError::UnknownValueError { .. }
+            | MapError::ChecksumFailed { .. }
+            | MapError::ShortAnswer { .. } => true,
+        }
+    }
 }
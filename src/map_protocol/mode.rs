@@ -0,0 +1,377 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Mutex, OnceLock},
+};
+
+use log::warn;
+use serde::Serialize;
+
+/// Declares the modes with their descriptions in Russian, as mapd had them. Each
+/// description is both the doc comment of its variant and the text returned by
+/// [`MapModeExtended::label_ru`].
+macro_rules! modes {
+    ($($(#[$attr:meta])* $variant:ident = $code:literal => $ru:literal,)*) => {
+        #[derive(PartialEq, PartialOrd, Debug, Clone, Serialize, Default)]
+        #[repr(u8)]
+        pub enum MapModeExtended {
+            $(#[doc = $ru] $(#[$attr])* $variant = $code,)*
+            /// Value the firmware reported that is not listed above, serialised as `{"Unknown": value}`
+            Unknown(u8),
+        }
+
+        impl MapModeExtended {
+            /// Description of the mode in Russian, the doc comment of the variant
+            pub fn label_ru(&self) -> &'static str {
+                match self {
+                    $(MapModeExtended::$variant => $ru,)*
+                    MapModeExtended::Unknown(_) => "Неизвестный режим",
+                }
+            }
+        }
+    };
+}
+
+modes! {
+    #[default]
+    PowerOff = 0 => "МАП выключен и нет сети на входе",
+    PowerOffExternalPowerPresent = 1 => "МАП выключен, но есть сеть на входе",
+    PowerOnGeneratingNoExternalPower = 2 => "МАП включен, генерация 220В от АКБ, нет сети на входе",
+    PowerOnTranslatingExternalPower = 3 => "МАП включен и транслирует сеть",
+    PowerOnTranslatingExternalPowerAndCharging = 4 => "МАП включен, транслирует сеть и заряжает АКБ",
+
+    // ------------ my extensions------------------------
+    ForcedGeneration = 10 => "Принудительная генерация",
+    SellingBackToGridMaxRateForcedGeneration = 11 => "Тарифная сеть, максимальный тариф, принудительная генерация",
+    SellingBackToGridMinRate = 12 => "Тарифная сеть, минимальный тариф",
+    TranslationECOPumping = 13 => "Трансляция + эко-подкачка",
+    TranslationSellingBackToGrid = 14 => "Трансляция + продажа в сеть",
+    WaitingForExternalCharge = 15 => "Ожидание внешнего заряда",
+    SellingBackToGridTranslationEcoPumping = 16 => "Тарифная сеть, трансляция + эко-подкачка",
+    SellingBackToGridTranslation = 17 => "Тарифная сеть, трансляция + продажа в сеть",
+    Pmax = 18 => "Режим подкачки Pmax",
+}
+
+impl From<u8> for MapModeExtended {
+    fn from(value: u8) -> Self {
+        use MapModeExtended::*;
+        match value {
+            0 => PowerOff,
+            1 => PowerOffExternalPowerPresent,
+            2 => PowerOnGeneratingNoExternalPower,
+            3 => PowerOnTranslatingExternalPower,
+            4 => PowerOnTranslatingExternalPowerAndCharging,
+            10 => ForcedGeneration,
+            11 => SellingBackToGridMaxRateForcedGeneration,
+            12 => SellingBackToGridMinRate,
+            13 => TranslationECOPumping,
+            14 => TranslationSellingBackToGrid,
+            15 => WaitingForExternalCharge,
+            16 => SellingBackToGridTranslationEcoPumping,
+            17 => SellingBackToGridTranslation,
+            18 => Pmax,
+            value => {
+                warn_unknown(value);
+                Unknown(value)
+            }
+        }
+    }
+}
+
+/// Logs an unknown mode the first time it is seen, polls repeat it every few seconds
+fn warn_unknown(value: u8) {
+    static SEEN: OnceLock<Mutex<BTreeSet<u8>>> = OnceLock::new();
+    let mut seen = SEEN.get_or_init(Default::default).lock().unwrap();
+    if seen.insert(value) {
+        warn!("MAP reported unknown mode {}, please report it", value);
+    }
+}
+
+impl MapModeExtended {
+    /// Number of the mode, as mapd published it
+    pub fn code(&self) -> u8 {
+        use MapModeExtended::*;
+        match self {
+            PowerOff => 0,
+            PowerOffExternalPowerPresent => 1,
+            PowerOnGeneratingNoExternalPower => 2,
+            PowerOnTranslatingExternalPower => 3,
+            PowerOnTranslatingExternalPowerAndCharging => 4,
+            ForcedGeneration => 10,
+            SellingBackToGridMaxRateForcedGeneration => 11,
+            SellingBackToGridMinRate => 12,
+            TranslationECOPumping => 13,
+            TranslationSellingBackToGrid => 14,
+            WaitingForExternalCharge => 15,
+            SellingBackToGridTranslationEcoPumping => 16,
+            SellingBackToGridTranslation => 17,
+            Pmax => 18,
+            Unknown(value) => *value,
+        }
+    }
+
+    /// Human readable name of the mode for dashboards
+    pub fn label(&self) -> &'static str {
+        use MapModeExtended::*;
+        match self {
+            PowerOff => "Off, no grid",
+            PowerOffExternalPowerPresent => "Off, grid present",
+            PowerOnGeneratingNoExternalPower => "Generating from battery, no grid",
+            PowerOnTranslatingExternalPower => "Translating grid",
+            PowerOnTranslatingExternalPowerAndCharging => "Translating grid and charging",
+            ForcedGeneration => "Forced generation",
+            SellingBackToGridMaxRateForcedGeneration => "Tariffs: maximum rate, forced generation",
+            SellingBackToGridMinRate => "Tariffs: minimum rate",
+            TranslationECOPumping => "Translating grid with ECO pumping",
+            TranslationSellingBackToGrid => "Translating grid and selling to it",
+            WaitingForExternalCharge => "Waiting for external charge",
+            SellingBackToGridTranslationEcoPumping => "Tariffs: translating grid with ECO pumping",
+            SellingBackToGridTranslation => "Tariffs: translating grid and selling to it",
+            Pmax => "Pumping up to Pmax",
+            Unknown(_) => "Unknown",
+        }
+    }
+}
+
+/// Operating mode with the ECO, tariff and Pmax extensions of mapd.
+///
+/// The MAP itself reports 0..=4. While it generates (2) or translates (3) the
+/// grid the EEPROM settings and flags tell what it is doing that for:
+/// * `net_up_eco` 0x13C - 0 forced generation or tariffs, 1 ECO pumping, 2 selling to the grid
+/// * `net_alg` 0x16B - 2 ECO, 3 tariffs
+/// * `net_up_load` 0x13B and `pmax_on` 0x58C - pumping up to Pmax when 1 and bit 1 set
+/// * `flag_eco` 0x585 - bit 0 waiting for external charge, bit 1 minimum tariff
+///
+/// mapd tested `Pmax_On & 2 > 0`, which C reads as `Pmax_On & 1`, the bit
+/// meant is the one with value 2.
+pub fn real_mode(
+    mode: MapModeExtended,
+    net_alg: u8,
+    flag_eco: u8,
+    net_up_eco: u8,
+    net_up_load: u8,
+    unet: i32,
+    pmax_on: u8,
+) -> MapModeExtended {
+    use MapModeExtended::*;
+
+    if mode != PowerOnGeneratingNoExternalPower && mode != PowerOnTranslatingExternalPower {
+        return mode;
+    }
+    let waiting = flag_eco & 1 != 0;
+    let min_rate = flag_eco & 2 != 0;
+    match net_up_eco {
+        // ECO forced generation or tariffs
+        0 => {
+            if net_up_load == 1 && pmax_on & 2 != 0 && unet > 100 {
+                return Pmax;
+            }
+            match (&mode, net_alg) {
+                (PowerOnGeneratingNoExternalPower, 2) if unet > 100 => ForcedGeneration,
+                (PowerOnGeneratingNoExternalPower, 3) if unet > 100 && !min_rate => {
+                    SellingBackToGridMaxRateForcedGeneration
+                }
+                (PowerOnGeneratingNoExternalPower, 3) if unet > 100 => SellingBackToGridMinRate,
+                _ => mode,
+            }
+        }
+        // ECO pumping
+        1 if mode == PowerOnTranslatingExternalPower => match net_alg {
+            2 if waiting => WaitingForExternalCharge,
+            2 => TranslationECOPumping,
+            3 if min_rate => SellingBackToGridMinRate,
+            3 => SellingBackToGridTranslationEcoPumping,
+            _ => mode,
+        },
+        // selling to the grid
+        2 if mode == PowerOnTranslatingExternalPower => match net_alg {
+            2 if waiting => WaitingForExternalCharge,
+            2 => TranslationSellingBackToGrid,
+            3 if min_rate => SellingBackToGridMinRate,
+            3 => SellingBackToGridTranslation,
+            _ => mode,
+        },
+        _ => mode,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_modes_keep_their_value() {
+        for code in (0..=4).chain(10..=18) {
+            assert_eq!(MapModeExtended::from(code).code(), code);
+        }
+        let mode = MapModeExtended::from(7);
+        assert_eq!(mode, MapModeExtended::Unknown(7));
+        assert_eq!(mode.code(), 7);
+        assert_eq!(serde_json::to_string(&mode).unwrap(), r#"{"Unknown":7}"#);
+        // resolution leaves it alone
+        assert_eq!(real_mode(mode.clone(), 2, 0, 0, 1, 230, 2), mode);
+    }
+
+    #[test]
+    fn real_mode_follows_mapd_table() {
+        use MapModeExtended::*;
+        let generating = PowerOnGeneratingNoExternalPower;
+        let translating = PowerOnTranslatingExternalPower;
+        // mode, net_alg, flag_eco, net_up_eco, net_up_load, unet, pmax_on, expected
+        let table = [
+            // modes the extensions do not touch
+            (PowerOff, 2, 0, 0, 1, 230, 2, PowerOff),
+            (
+                PowerOffExternalPowerPresent,
+                2,
+                0,
+                0,
+                1,
+                230,
+                2,
+                PowerOffExternalPowerPresent,
+            ),
+            (
+                PowerOnTranslatingExternalPowerAndCharging,
+                2,
+                0,
+                1,
+                0,
+                230,
+                0,
+                PowerOnTranslatingExternalPowerAndCharging,
+            ),
+            // ECO forced generation or tariffs
+            (generating.clone(), 2, 0, 0, 1, 230, 2, Pmax),
+            (translating.clone(), 3, 0, 0, 1, 230, 3, Pmax),
+            (generating.clone(), 2, 0, 0, 1, 230, 1, ForcedGeneration),
+            (generating.clone(), 2, 0, 0, 0, 230, 2, ForcedGeneration),
+            (generating.clone(), 2, 0, 0, 1, 0, 2, generating.clone()),
+            (
+                generating.clone(),
+                3,
+                0,
+                0,
+                0,
+                230,
+                0,
+                SellingBackToGridMaxRateForcedGeneration,
+            ),
+            (
+                generating.clone(),
+                3,
+                2,
+                0,
+                0,
+                230,
+                0,
+                SellingBackToGridMinRate,
+            ),
+            (generating.clone(), 3, 2, 0, 0, 0, 0, generating.clone()),
+            (generating.clone(), 1, 0, 0, 0, 230, 0, generating.clone()),
+            (translating.clone(), 2, 0, 0, 0, 230, 0, translating.clone()),
+            // ECO pumping
+            (
+                translating.clone(),
+                2,
+                1,
+                1,
+                0,
+                230,
+                0,
+                WaitingForExternalCharge,
+            ),
+            (
+                translating.clone(),
+                2,
+                0,
+                1,
+                0,
+                230,
+                0,
+                TranslationECOPumping,
+            ),
+            (
+                translating.clone(),
+                3,
+                2,
+                1,
+                0,
+                230,
+                0,
+                SellingBackToGridMinRate,
+            ),
+            (
+                translating.clone(),
+                3,
+                0,
+                1,
+                0,
+                230,
+                0,
+                SellingBackToGridTranslationEcoPumping,
+            ),
+            (translating.clone(), 1, 0, 1, 0, 230, 0, translating.clone()),
+            (generating.clone(), 2, 0, 1, 1, 230, 2, generating.clone()),
+            // selling to the grid
+            (
+                translating.clone(),
+                2,
+                1,
+                2,
+                0,
+                230,
+                0,
+                WaitingForExternalCharge,
+            ),
+            (
+                translating.clone(),
+                2,
+                0,
+                2,
+                0,
+                230,
+                0,
+                TranslationSellingBackToGrid,
+            ),
+            (
+                translating.clone(),
+                3,
+                2,
+                2,
+                0,
+                230,
+                0,
+                SellingBackToGridMinRate,
+            ),
+            (
+                translating.clone(),
+                3,
+                0,
+                2,
+                0,
+                230,
+                0,
+                SellingBackToGridTranslation,
+            ),
+            (generating.clone(), 2, 0, 2, 0, 230, 0, generating.clone()),
+            // unknown ECO setting
+            (translating.clone(), 2, 0, 3, 1, 230, 2, translating.clone()),
+        ];
+        for (mode, net_alg, flag_eco, net_up_eco, net_up_load, unet, pmax_on, expected) in table {
+            assert_eq!(
+                real_mode(
+                    mode.clone(),
+                    net_alg,
+                    flag_eco,
+                    net_up_eco,
+                    net_up_load,
+                    unet,
+                    pmax_on
+                ),
+                expected,
+                "{mode:?} net_alg {net_alg} flag_eco {flag_eco} net_up_eco {net_up_eco} \
+                 net_up_load {net_up_load} unet {unet} pmax_on {pmax_on}"
+            );
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Where the values the bridge knows about live in MAP memory and how they are decoded.
+//!
+//! The built-in map is `registers.toml` next to this file. `--register-map FILE`
+//! adds registers in the same format or replaces built-in ones with the same name,
+//! so a register of a new firmware is supported without changing the code:
+//!
+//! ```toml
+//! [[register]]
+//! name = "u_acc"        # MapInfo field, unknown names are published as extra fields
+//! addr = 0x405          # first byte; registers without it are computed by the bridge
+//! width = 2             # 1 to 4 bytes, default 1
+//! endian = "big"        # "little" (default) or "big"
+//! sign = "unsigned"     # "unsigned" (default), "twos_complement" or "sign_magnitude"
+//! mask = 0xffff         # bits of the raw value to keep
+//! scale = 0.1           # value = raw * scale + offset
+//! offset = 0
+//! offset_nonzero_only = false # keep 0 as 0, e.g. a voltage that is not measured
+//! setting = false       # EEPROM setting, not a status value
+//! valid = [0, 2]        # raw values a setting may hold, others make the firmware unknown
+//! layout = "extended"   # decode only on this firmware layout, see `firmware`
+//! unit = "V"
+//! device_class = "voltage"     # Home Assistant metadata
+//! state_class = "measurement"  # `total_increasing` makes a Prometheus counter
+//! description = "Battery voltage"
+//! ```
+//!
+//! `registers` prints the resulting map as a markdown table.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use super::firmware::Layout;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signedness {
+    #[default]
+    Unsigned,
+    TwosComplement,
+    /// The top bit is the sign, the rest the magnitude
+    SignMagnitude,
+}
+
+/// One value in MAP memory, see the module documentation for the fields
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Register {
+    pub name: String,
+    #[serde(default)]
+    pub addr: Option<u16>,
+    #[serde(default = "default_width")]
+    pub width: u8,
+    #[serde(default)]
+    pub endian: Endian,
+    #[serde(default)]
+    pub sign: Signedness,
+    #[serde(default)]
+    pub mask: Option<u32>,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub offset_nonzero_only: bool,
+    #[serde(default)]
+    pub setting: bool,
+    #[serde(default)]
+    pub valid: Option<[u32; 2]>,
+    #[serde(default)]
+    pub layout: Option<Layout>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub device_class: Option<String>,
+    #[serde(default)]
+    pub state_class: Option<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+fn default_width() -> u8 {
+    1
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl Register {
+    /// Status value published by the bridge, as opposed to an EEPROM setting
+    pub fn is_status(&self) -> bool {
+        !self.setting
+    }
+
+    /// Bytes of the register in `memory` read from `addr`, if it lies there completely
+    pub fn bytes_in<'a>(&self, addr: u16, memory: &'a [u8]) -> Option<&'a [u8]> {
+        let start = (self.addr? as usize).checked_sub(addr as usize)?;
+        memory.get(start..start + self.width as usize)
+    }
+
+    /// Masked value before sign handling and scaling
+    fn raw(&self, bytes: &[u8]) -> u32 {
+        let bytes = &bytes[..self.width as usize];
+        let raw = match self.endian {
+            Endian::Little => bytes
+                .iter()
+                .rev()
+                .fold(0u32, |raw, byte| raw << 8 | *byte as u32),
+            Endian::Big => bytes.iter().fold(0u32, |raw, byte| raw << 8 | *byte as u32),
+        };
+        raw & self.mask.unwrap_or(u32::MAX)
+    }
+
+    /// Whether the raw value is in the `valid` range, registers without one
+    /// or not lying in `memory` are always valid
+    pub fn is_valid_in(&self, addr: u16, memory: &[u8]) -> bool {
+        match (self.valid, self.bytes_in(addr, memory)) {
+            (Some([min, max]), Some(bytes)) => (min..=max).contains(&self.raw(bytes)),
+            _ => true,
+        }
+    }
+
+    /// Decodes the value from its bytes, `bytes` must hold at least `width` of them
+    pub fn decode(&self, bytes: &[u8]) -> f64 {
+        let raw = self.raw(bytes);
+        let bits = self.width as u32 * 8;
+        let value = match self.sign {
+            Signedness::Unsigned => raw as f64,
+            Signedness::TwosComplement => {
+                let shift = 32 - bits;
+                ((raw << shift) as i32 >> shift) as f64
+            }
+            Signedness::SignMagnitude => {
+                let sign_bit = 1u32 << (bits - 1);
+                if raw & sign_bit == 0 {
+                    raw as f64
+                } else {
+                    -((raw & !sign_bit) as f64)
+                }
+            }
+        };
+        if value == 0.0 && self.offset_nonzero_only {
+            return 0.0;
+        }
+        value * self.scale + self.offset
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!(
+                "register name `{}` must be letters, digits and `_`",
+                self.name
+            );
+        }
+        if !(1..=4).contains(&self.width) {
+            bail!(
+                "register {} is {} bytes wide, 1 to 4 are supported",
+                self.name,
+                self.width
+            );
+        }
+        if let Some(addr) = self.addr {
+            if addr as u32 + self.width as u32 > 0x10000 {
+                bail!(
+                    "register {} does not fit the 16 bit address space",
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterFile {
+    #[serde(default)]
+    register: Vec<Register>,
+}
+
+/// Every register the bridge decodes, in the order they are documented
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterMap {
+    registers: Vec<Register>,
+}
+
+impl RegisterMap {
+    fn parse(text: &str) -> anyhow::Result<Vec<Register>> {
+        let file: RegisterFile = toml::from_str(text)?;
+        for register in &file.register {
+            register.check()?;
+        }
+        Ok(file.register)
+    }
+
+    /// The map in `registers.toml`
+    pub fn builtin() -> Arc<Self> {
+        static BUILTIN: OnceLock<Arc<RegisterMap>> = OnceLock::new();
+        BUILTIN
+            .get_or_init(|| {
+                let registers = Self::parse(include_str!("registers.toml"))
+                    .expect("built-in register map is valid");
+                Arc::new(Self { registers })
+            })
+            .clone()
+    }
+
+    /// The built-in map with the registers of `path` added or replacing those with the same name
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Arc<Self>> {
+        let Some(path) = path else {
+            return Ok(Self::builtin());
+        };
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read register map {}", path.display()))?;
+        let user = Self::parse(&text)
+            .with_context(|| format!("cannot parse register map {}", path.display()))?;
+        let mut map = Self::builtin().as_ref().clone();
+        map.merge(user);
+        Ok(Arc::new(map))
+    }
+
+    fn merge(&mut self, registers: Vec<Register>) {
+        for register in registers {
+            match self.registers.iter_mut().find(|r| r.name == register.name) {
+                Some(existing) => *existing = register,
+                None => self.registers.push(register),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Register> {
+        self.registers.iter()
+    }
+
+    /// Registers lying at least partly in `addr..addr + len`
+    pub fn in_range(&self, addr: u16, len: u16) -> impl Iterator<Item = &Register> {
+        let end = addr as u32 + len as u32;
+        self.registers.iter().filter(move |r| {
+            r.addr.is_some_and(|start| {
+                (start as u32) < end && start as u32 + r.width as u32 > addr as u32
+            })
+        })
+    }
+
+    /// Status values of the registers lying completely in `memory`, which was
+    /// read from `addr`, leaving out those meant for another firmware layout
+    pub fn decode<'a>(
+        &'a self,
+        addr: u16,
+        memory: &'a [u8],
+        layout: Layout,
+    ) -> impl Iterator<Item = (&'a Register, f64)> + 'a {
+        self.registers.iter().filter_map(move |register| {
+            let bytes = register.bytes_in(addr, memory)?;
+            (register.is_status() && register.layout.is_none_or(|only| only == layout))
+                .then(|| (register, register.decode(bytes)))
+        })
+    }
+
+    /// Documentation of the map as a markdown table
+    pub fn write_markdown(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "| Name | Address | Bytes | Decoding | Unit | Description |"
+        )?;
+        writeln!(out, "|---|---|---|---|---|---|")?;
+        for register in &self.registers {
+            let (addr, width) = match register.addr {
+                Some(addr) => (format!("`{addr:#05x}`"), register.width.to_string()),
+                None => ("computed".into(), String::new()),
+            };
+            let mut decoding = Vec::new();
+            if register.width > 1 {
+                decoding.push(format!("{:?}", register.endian).to_lowercase());
+            }
+            match register.sign {
+                Signedness::Unsigned => {}
+                Signedness::TwosComplement => decoding.push("two's complement".into()),
+                Signedness::SignMagnitude => decoding.push("sign and magnitude".into()),
+            }
+            if let Some(mask) = register.mask {
+                decoding.push(format!("& {mask:#x}"));
+            }
+            if register.scale != 1.0 {
+                decoding.push(format!("× {}", register.scale));
+            }
+            if register.offset != 0.0 {
+                let when = if register.offset_nonzero_only {
+                    " unless 0"
+                } else {
+                    ""
+                };
+                decoding.push(format!("{:+}{when}", register.offset));
+            }
+            if register.setting {
+                decoding.push("EEPROM setting".into());
+            }
+            if let Some([min, max]) = register.valid {
+                decoding.push(format!("valid {min}..={max}"));
+            }
+            if let Some(layout) = register.layout {
+                decoding.push(format!("{layout} firmware only"));
+            }
+            writeln!(
+                out,
+                "| `{}` | {} | {} | {} | {} | {} |",
+                register.name,
+                addr,
+                width,
+                decoding.join(", "),
+                register.unit.as_deref().unwrap_or(""),
+                register.description
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_status_block_like_mapd() {
+        let map = RegisterMap::builtin();
+        let mut memory = [0u8; 0x100];
+        memory[0x05..0x07].copy_from_slice(&[0x02, 0x13]); // u_acc 0x405, big endian
+        memory[0x22] = 0; // u_net 0x422, no grid
+        memory[0x27] = 120; // u_ou_t_med 0x427
+        memory[0x29] = 0; // u_net_limit 0x429, offset always added
+        memory[0x2E] = 75; // temp_grad0 0x42E
+        memory[0x32..0x34].copy_from_slice(&[0x28, 0x01]); // i_acc_med_a_u16 0x432
+        memory[0x4D..0x50].copy_from_slice(&[0x01, 0x02, 0x03]); // e_net 0x44D
+        let values: Vec<(String, f64)> = map
+            .decode(0x400, &memory, Layout::Extended)
+            .map(|(register, value)| (register.name.clone(), value))
+            .collect();
+        let value = |name: &str| {
+            values
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| *value)
+                .unwrap()
+        };
+        assert!((value("u_acc") - 53.1).abs() < 1e-9);
+        assert_eq!(value("u_net"), 0.0);
+        assert_eq!(value("u_ou_t_med"), 220.0);
+        assert_eq!(value("u_net_limit"), 100.0);
+        assert_eq!(value("temp_grad0"), 25.0);
+        assert_eq!(
+            value("i_acc_med_a_u16"),
+            0x01 as f64 * 16.0 + 0x28 as f64 / 16.0
+        );
+        assert_eq!(value("e_net"), 0x030201 as f64);
+        // settings and the 0x527 block are not in this read
+        assert!(values
+            .iter()
+            .all(|(name, _)| name != "phase_config" && name != "i_ph1"));
+
+        let mut block = [0u8; 0x60];
+        block[0x01..0x03].copy_from_slice(&[0x2C, 0x81]); // i_ph1 0x528, negative
+        block[0x5F] = 0b11; // relays 0x586
+        let values: Vec<(String, f64)> = map
+            .decode(0x527, &block, Layout::Extended)
+            .map(|(register, value)| (register.name.clone(), value))
+            .collect();
+        assert!(values.contains(&("relay1".into(), 1.0)));
+        assert!(values.contains(&("relay2".into(), 2.0)));
+        let i_ph1 = values.iter().find(|(name, _)| name == "i_ph1").unwrap().1;
+        assert!((i_ph1 + 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn user_file_adds_and_replaces_registers() {
+        let mut map = RegisterMap::builtin().as_ref().clone();
+        map.merge(
+            RegisterMap::parse(
+                r#"
+                [[register]]
+                name = "temp_grad1"
+                addr = 0x42F
+                sign = "twos_complement"
+
+                [[register]]
+                name = "u_pv"
+                addr = 0x440
+                width = 2
+                scale = 0.1
+                "#,
+            )
+            .unwrap(),
+        );
+        let temp = map.iter().find(|r| r.name == "temp_grad1").unwrap();
+        assert_eq!(temp.decode(&[0xFE]), -2.0);
+        assert!(map.iter().any(|r| r.name == "u_pv"));
+        assert!(RegisterMap::parse("[[register]]\nname = \"x\"\nwidth = 5").is_err());
+        assert!(RegisterMap::parse("[[register]]\nname = \"x\"\nbits = 5").is_err());
+    }
+}
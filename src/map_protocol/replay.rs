@@ -0,0 +1,122 @@
+use std::io::{self, Read, Write};
+
+use super::{
+    capture::{CaptureRecord, Verdict},
+    low_level::Transport,
+};
+
+/// Plays the MAP side of a captured session back, so the whole pipeline can
+/// run offline against real field data.
+///
+/// Every attempt of the capture is replayed in order: the bytes written by the
+/// bridge have to match what was sent in the recorded session, otherwise the
+/// replay fails with [`io::ErrorKind::InvalidData`]; reads return what was
+/// received. An attempt that ended with an IO error times out again once its
+/// bytes are used up, and the end of the capture is reported as a
+/// non-transient error so loops stop instead of retrying forever.
+#[derive(Debug)]
+pub struct Replay {
+    records: Vec<CaptureRecord>,
+    index: usize,
+    sent: usize,
+    received: usize,
+}
+
+impl Replay {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        Self {
+            records,
+            index: 0,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Records of one port of a capture, all of them when it holds a single port
+    pub fn for_port(records: Vec<CaptureRecord>, port: &str) -> Self {
+        let single_port = records.windows(2).all(|pair| pair[0].port == pair[1].port);
+        if single_port {
+            return Self::new(records);
+        }
+        Self::new(
+            records
+                .into_iter()
+                .filter(|record| record.port == port)
+                .collect(),
+        )
+    }
+
+    fn touched(&self) -> bool {
+        self.sent > 0 || self.received > 0
+    }
+
+    fn next_attempt(&mut self) {
+        self.index += 1;
+        self.sent = 0;
+        self.received = 0;
+    }
+
+    fn current(&self) -> io::Result<&CaptureRecord> {
+        self.records
+            .get(self.index)
+            .ok_or_else(|| io::Error::other("end of capture"))
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let record = self.current()?;
+        let rest = &record.received[self.received..];
+        if rest.is_empty() || buf.is_empty() {
+            return Err(match record.verdict {
+                Verdict::IoError => io::ErrorKind::TimedOut.into(),
+                _ => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("attempt {} of the capture has no more bytes", self.index),
+                ),
+            });
+        }
+        let count = rest.len().min(buf.len());
+        buf[..count].copy_from_slice(&rest[..count]);
+        self.received += count;
+        Ok(count)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.touched() && self.sent == self.current()?.sent.len() {
+            // a new command after a complete exchange
+            self.next_attempt();
+        }
+        let index = self.index;
+        let offset = self.sent;
+        let expected = &self.current()?.sent[offset..];
+        if !expected.starts_with(buf) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "replay diverged at byte {offset} of attempt {index}: sent {buf:02x?}, capture has {:02x?}",
+                    &expected[..expected.len().min(buf.len())]
+                ),
+            ));
+        }
+        self.sent += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    /// The bridge flushes the port before repeating a failed command, so the
+    /// rest of the failed attempt is dropped
+    fn clear(&mut self) -> io::Result<()> {
+        if self.touched() {
+            self.next_attempt();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrono::Local;
+use clap::ValueEnum;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    map_actor::MapHandle,
+    map_protocol::{high_level::MapInfo, mode::MapModeExtended},
+    soc::Chemistry,
+};
+
+const CLEAR: &str = "\x1b[H\x1b[2J";
+const HIDE_CURSOR: &str = "\x1b[?25l";
+const SHOW_CURSOR: &str = "\x1b[?25h";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+const GAUGE_WIDTH: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Language {
+    En,
+    Ru,
+}
+
+struct Texts {
+    battery: &'static str,
+    charging: &'static str,
+    discharging: &'static str,
+    grid: &'static str,
+    no_grid: &'static str,
+    load: &'static str,
+    output: &'static str,
+    temperatures: &'static str,
+    battery_temperature: &'static str,
+    map_temperature: &'static str,
+    sensor_temperature: &'static str,
+    relays: &'static str,
+    on: &'static str,
+    off: &'static str,
+    errors: &'static str,
+    none: &'static str,
+    cells: &'static str,
+    unreachable: &'static str,
+    quit: &'static str,
+}
+
+const EN: Texts = Texts {
+    battery: "Battery",
+    charging: "charging",
+    discharging: "discharging",
+    grid: "Grid",
+    no_grid: "no grid",
+    load: "Load",
+    output: "output",
+    temperatures: "Temperatures",
+    battery_temperature: "battery",
+    map_temperature: "MAP",
+    sensor_temperature: "sensor 1",
+    relays: "Relays",
+    on: "on",
+    off: "off",
+    errors: "Errors",
+    none: "none",
+    cells: "Cells",
+    unreachable: "cannot read the MAP",
+    quit: "Ctrl-C to quit",
+};
+
+const RU: Texts = Texts {
+    battery: "АКБ",
+    charging: "заряд",
+    discharging: "разряд",
+    grid: "Сеть",
+    no_grid: "нет сети",
+    load: "Нагрузка",
+    output: "выход",
+    temperatures: "Температуры",
+    battery_temperature: "АКБ",
+    map_temperature: "МАП",
+    sensor_temperature: "датчик 1",
+    relays: "Реле",
+    on: "вкл",
+    off: "выкл",
+    errors: "Ошибки",
+    none: "нет",
+    cells: "Ячейки",
+    unreachable: "нет связи с МАП",
+    quit: "Ctrl-C для выхода",
+};
+
+impl Language {
+    fn texts(self) -> &'static Texts {
+        match self {
+            Language::En => &EN,
+            Language::Ru => &RU,
+        }
+    }
+
+    fn mode(self, mode: &MapModeExtended) -> &'static str {
+        match self {
+            Language::En => mode.label(),
+            Language::Ru => mode.label_ru(),
+        }
+    }
+}
+
+/// A MAP shown on the dashboard
+pub struct Monitored {
+    pub id: String,
+    pub map: MapHandle,
+    pub chemistry: Chemistry,
+    /// Battery cells in series
+    pub cells: u32,
+}
+
+fn gauge(level: f32) -> String {
+    let filled = (level * GAUGE_WIDTH as f32).round() as usize;
+    format!(
+        "[{}{}]",
+        "#".repeat(filled),
+        "-".repeat(GAUGE_WIDTH - filled)
+    )
+}
+
+/// Current around the middle of the gauge, charging to the right
+fn current_gauge(current: f32) -> String {
+    let half = GAUGE_WIDTH / 2;
+    let full_scale = (current.abs() / 50.0).ceil().max(1.0) * 50.0;
+    let cells = ((current.abs() / full_scale) * half as f32).round() as usize;
+    let (left, right) = if current < 0.0 {
+        (
+            " ".repeat(half - cells) + &"<".repeat(cells),
+            " ".repeat(half),
+        )
+    } else {
+        (
+            " ".repeat(half),
+            ">".repeat(cells) + &" ".repeat(half - cells),
+        )
+    };
+    format!("[{left}|{right}] ±{full_scale:.0} A")
+}
+
+fn on_off(texts: &Texts, state: u8) -> String {
+    match state {
+        0 => texts.off.to_string(),
+        _ => format!("{GREEN}{}{RESET}", texts.on),
+    }
+}
+
+/// One MAP on the dashboard
+fn render(
+    screen: &mut String,
+    id: &str,
+    chemistry: Chemistry,
+    cells: u32,
+    sample: &Result<MapInfo, String>,
+    language: Language,
+) {
+    let texts = language.texts();
+    let _ = writeln!(screen, "{BOLD}MAP {id}{RESET}");
+    let m = match sample {
+        Ok(m) => m,
+        Err(error) => {
+            let _ = writeln!(screen, "  {RED}{}: {error}{RESET}\n", texts.unreachable);
+            return;
+        }
+    };
+    let _ = writeln!(
+        screen,
+        "  {BOLD}{}{RESET} ({})",
+        language.mode(&m.mode),
+        m.mode_raw
+    );
+
+    let direction = if m.battery_current >= 0.0 {
+        texts.charging
+    } else {
+        texts.discharging
+    };
+    let _ = writeln!(
+        screen,
+        "  {:<14}{:>6.1} V   {}",
+        texts.battery,
+        m.u_acc,
+        gauge(chemistry.voltage_level(m.u_acc, cells))
+    );
+    let _ = writeln!(
+        screen,
+        "  {:<14}{:>6.1} A   {} {direction}",
+        "",
+        m.battery_current,
+        current_gauge(m.battery_current)
+    );
+
+    if m.u_net > 0 {
+        let mut grid = format!("{} V  {} A  {} W", m.u_net, m.i_net, m.p_net);
+        if m.tf_net > 0 {
+            let _ = write!(grid, "  {:.1} Hz", 6250.0 / m.tf_net as f32);
+        }
+        let _ = writeln!(screen, "  {:<14}{GREEN}{grid}{RESET}", texts.grid);
+    } else {
+        let _ = writeln!(
+            screen,
+            "  {:<14}{YELLOW}{}{RESET}",
+            texts.grid, texts.no_grid
+        );
+    }
+    let _ = writeln!(
+        screen,
+        "  {:<14}{} W  {} {} V",
+        texts.load, m.p_load, texts.output, m.u_ou_t_med
+    );
+    let _ = writeln!(
+        screen,
+        "  {:<14}{} {} °C  {} {} °C  {} {} °C",
+        texts.temperatures,
+        texts.battery_temperature,
+        m.temp_grad0,
+        texts.map_temperature,
+        m.temp_grad2,
+        texts.sensor_temperature,
+        m.temp_grad1
+    );
+    let _ = writeln!(
+        screen,
+        "  {:<14}1: {}  2: {}",
+        texts.relays,
+        on_off(texts, m.relay1),
+        on_off(texts, m.relay2)
+    );
+    if !m.bms.is_empty() {
+        let voltages = m.bms.iter().map(|cell| cell.v);
+        let min = voltages.clone().fold(f32::INFINITY, f32::min);
+        let max = voltages.fold(f32::NEG_INFINITY, f32::max);
+        let _ = writeln!(
+            screen,
+            "  {:<14}{} × {min:.2}..{max:.2} V",
+            texts.cells,
+            m.bms.len()
+        );
+    }
+    let errors: Vec<String> = m
+        .errors()
+        .into_iter()
+        .map(|(name, code)| format!("{name}={code}"))
+        .collect();
+    if errors.is_empty() {
+        let _ = writeln!(screen, "  {:<14}{}", texts.errors, texts.none);
+    } else {
+        let _ = writeln!(
+            screen,
+            "  {:<14}{RED}{}{RESET}",
+            texts.errors,
+            errors.join("  ")
+        );
+    }
+    let _ = writeln!(screen);
+}
+
+/// Redraws the dashboard of every MAP each `interval` until Ctrl-C
+pub async fn run(
+    units: &[Monitored],
+    interval: Duration,
+    language: Language,
+) -> anyhow::Result<()> {
+    // registered once, so Ctrl-C while a MAP does not answer is not lost
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    print!("{HIDE_CURSOR}");
+    let result = async {
+        loop {
+            let mut screen = format!(
+                "{CLEAR}{}   {}\n\n",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                language.texts().quit
+            );
+            for unit in units {
+                let sample = tokio::select! {
+                    sample = unit.map.read_status() => sample.map_err(|error| format!("{error:#}")),
+                    _ = interrupt.recv() => return anyhow::Ok(()),
+                };
+                render(
+                    &mut screen,
+                    &unit.id,
+                    unit.chemistry,
+                    unit.cells,
+                    &sample,
+                    language,
+                );
+            }
+            let mut out = io::stdout().lock();
+            out.write_all(screen.as_bytes())?;
+            out.flush()?;
+            drop(out);
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = interrupt.recv() => return anyhow::Ok(()),
+            }
+        }
+    }
+    .await;
+    print!("{SHOW_CURSOR}");
+    io::stdout().flush()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_in_both_languages() {
+        let sample = Ok(MapInfo {
+            mode: MapModeExtended::PowerOnTranslatingExternalPowerAndCharging,
+            mode_raw: 4,
+            u_acc: 26.4,
+            battery_current: 12.5,
+            u_net: 230,
+            rs_err_job: 5,
+            ..Default::default()
+        });
+        let mut screen = String::new();
+        render(
+            &mut screen,
+            "1",
+            Chemistry::LeadAcid,
+            12,
+            &sample,
+            Language::En,
+        );
+        assert!(screen.contains("Translating grid and charging"));
+        assert!(screen.contains("26.4 V"));
+        assert!(screen.contains("charging"));
+        assert!(screen.contains("rs_err_job=5"));
+
+        let mut screen = String::new();
+        render(
+            &mut screen,
+            "1",
+            Chemistry::LeadAcid,
+            12,
+            &sample,
+            Language::Ru,
+        );
+        assert!(screen.contains("МАП включен, транслирует сеть и заряжает АКБ"));
+        assert!(screen.contains("заряд"));
+
+        let mut screen = String::new();
+        render(
+            &mut screen,
+            "2",
+            Chemistry::LeadAcid,
+            12,
+            &Err("timed out".into()),
+            Language::En,
+        );
+        assert!(screen.contains("cannot read the MAP: timed out"));
+    }
+
+    #[test]
+    fn gauges_scale() {
+        let lead_acid = Chemistry::LeadAcid;
+        assert_eq!(lead_acid.voltage_level(10.0, 6), 0.0);
+        assert_eq!(lead_acid.voltage_level(28.8, 12), 1.0);
+        // 16 LiFePO4 cells run from 40 V to 55.2 V
+        let lifepo4 = Chemistry::Lifepo4;
+        assert_eq!(lifepo4.voltage_level(40.0, 16), 0.0);
+        assert_eq!(lifepo4.voltage_level(55.2, 16), 1.0);
+        assert_eq!(gauge(0.5).matches('#').count(), GAUGE_WIDTH / 2);
+        assert!(current_gauge(-60.0).contains("±100 A"));
+    }
+}
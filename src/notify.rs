@@ -0,0 +1,493 @@
+//! Notifications about alarms, sent to HTTP webhooks and local commands.
+//!
+//! A rule watches one field of the samples. It fires when its condition starts
+//! to hold and is cleared once the value is `hysteresis` back on the other side
+//! of the threshold. An alarm starting less than `cooldown` after the rule last
+//! fired waits, and fires when the cooldown ends if it is still on:
+//!
+//! ```toml
+//! [notify]
+//! webhook = ["https://example.com/hooks/map"]
+//! command = ["/usr/local/bin/map-alarm"]
+//!
+//! [[notify.rule]]
+//! name = "battery low"
+//! field = "u_acc"
+//! condition = "below"
+//! threshold = 46.0
+//! hysteresis = 1.0
+//! cooldown = "30min"
+//! message = "MAP {unit}: battery at {value} V, {mode}, errors: {errors}"
+//! ```
+//!
+//! The message may use `{unit}`, `{rule}`, `{field}`, `{value}`, `{threshold}`,
+//! `{mode}`, `{errors}` and any other field of the sample. Webhooks get the
+//! [`Notification`] as JSON in a POST request, commands run with `sh -c` and
+//! find it in `MAP_UNIT`, `MAP_RULE`, `MAP_FIELD`, `MAP_VALUE` and `MAP_MESSAGE`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{Local, SecondsFormat};
+use log::{info, warn};
+use openssl::ssl::{SslConnector, SslMethod};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{config::deserialize_duration, map_protocol::high_level::MapInfo};
+
+const DEFAULT_MESSAGE: &str = "MAP {unit}: {rule}: {field} is {value}, {mode}, errors: {errors}";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `[notify]` of the config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    webhook: Vec<String>,
+    #[serde(default)]
+    command: Vec<String>,
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Condition {
+    Above,
+    Below,
+    Equal,
+    NotEqual,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    name: String,
+    field: String,
+    condition: Condition,
+    threshold: f64,
+    /// How far back past the threshold the value has to go to clear the alarm
+    #[serde(default)]
+    hysteresis: f64,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    cooldown: Option<Duration>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl Rule {
+    /// Whether the alarm is on for `value`, given whether it was on before
+    fn active(&self, value: f64, was_active: bool) -> bool {
+        let margin = if was_active { self.hysteresis } else { 0.0 };
+        match self.condition {
+            Condition::Above => value > self.threshold - margin,
+            Condition::Below => value < self.threshold + margin,
+            Condition::Equal => value == self.threshold,
+            Condition::NotEqual => value != self.threshold,
+        }
+    }
+}
+
+/// Payload of a webhook request
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    pub timestamp: String,
+    pub unit: String,
+    pub rule: String,
+    pub field: String,
+    pub value: f64,
+    pub threshold: f64,
+    pub mode: &'static str,
+    pub errors: BTreeMap<&'static str, u8>,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    active: bool,
+    /// The alarm started during the cooldown and was not sent yet
+    pending: bool,
+    fired: Option<Instant>,
+}
+
+/// Evaluates the rules on every sample and sends the notifications
+#[derive(Debug, Default)]
+pub struct Notifier {
+    config: NotifyConfig,
+    /// By unit id and rule index
+    states: HashMap<(String, usize), RuleState>,
+}
+
+impl Notifier {
+    /// `known_fields` are the names rules may watch
+    pub fn new(config: NotifyConfig, known_fields: &[String]) -> anyhow::Result<Self> {
+        for rule in &config.rule {
+            if !known_fields.contains(&rule.field) {
+                bail!(
+                    "unknown field `{}` in notify rule `{}`",
+                    rule.field,
+                    rule.name
+                );
+            }
+        }
+        for url in &config.webhook {
+            Webhook::parse(url)?;
+        }
+        Ok(Self {
+            config,
+            states: HashMap::new(),
+        })
+    }
+
+    /// Notifications due for a sample of unit `id`
+    pub fn check(&mut self, id: &str, sample: &MapInfo, now: Instant) -> Vec<Notification> {
+        // through the JSON text, so f32 fields keep the value the MAP reported
+        let Ok(fields) = sample.fields() else {
+            return Vec::new();
+        };
+        let mut notifications = Vec::new();
+        for (index, rule) in self.config.rule.iter().enumerate() {
+            let Some(value) = fields.get(&rule.field).and_then(Value::as_f64) else {
+                continue;
+            };
+            let state = self.states.entry((id.to_string(), index)).or_default();
+            let active = rule.active(value, state.active);
+            state.pending = active && (state.pending || !state.active);
+            state.active = active;
+            let cooling_down = match (state.fired, rule.cooldown) {
+                (Some(fired), Some(cooldown)) => now.saturating_duration_since(fired) < cooldown,
+                _ => false,
+            };
+            if !state.pending || cooling_down {
+                continue;
+            }
+            state.pending = false;
+            state.fired = Some(now);
+            let template = rule.message.as_deref().unwrap_or(DEFAULT_MESSAGE);
+            let mut notification = Notification {
+                timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+                unit: id.to_string(),
+                rule: rule.name.clone(),
+                field: rule.field.clone(),
+                value,
+                threshold: rule.threshold,
+                mode: sample.mode.label(),
+                errors: sample.errors().into_iter().collect(),
+                message: String::new(),
+            };
+            notification.message = render(template, &notification, &fields);
+            notifications.push(notification);
+        }
+        notifications
+    }
+
+    /// Sends a notification to every webhook and command on threads of their own,
+    /// failures are logged
+    pub fn send(&self, notification: &Notification) {
+        info!("unit {}: {}", notification.unit, notification.message);
+        for url in &self.config.webhook {
+            let url = url.clone();
+            let body = serde_json::to_vec(notification).unwrap_or_default();
+            thread::spawn(move || {
+                if let Err(error) = Webhook::parse(&url).and_then(|webhook| webhook.post(&body)) {
+                    warn!("cannot notify {}: {:#}", url, error);
+                }
+            });
+        }
+        for command in &self.config.command {
+            let mut process = Command::new("sh");
+            process
+                .arg("-c")
+                .arg(command)
+                .env("MAP_UNIT", &notification.unit)
+                .env("MAP_RULE", &notification.rule)
+                .env("MAP_FIELD", &notification.field)
+                .env("MAP_VALUE", notification.value.to_string())
+                .env("MAP_MESSAGE", &notification.message);
+            let command = command.clone();
+            thread::spawn(move || match process.status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("notify command `{}` failed: {}", command, status),
+                Err(error) => warn!("cannot run notify command `{}`: {}", command, error),
+            });
+        }
+    }
+}
+
+/// Replaces `{name}` with the fields of the notification or the sample,
+/// unknown names are left alone
+fn render(template: &str, notification: &Notification, fields: &Map<String, Value>) -> String {
+    let errors = match notification.errors.is_empty() {
+        true => "none".to_string(),
+        false => notification
+            .errors
+            .iter()
+            .map(|(name, code)| format!("{name}={code}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &rest[start + 1..start + end];
+        let value = match name {
+            "unit" => Some(notification.unit.clone()),
+            "rule" => Some(notification.rule.clone()),
+            "field" => Some(notification.field.clone()),
+            "value" => Some(notification.value.to_string()),
+            "threshold" => Some(notification.threshold.to_string()),
+            "mode" => Some(notification.mode.to_string()),
+            "errors" => Some(errors.clone()),
+            _ => fields.get(name).map(|value| match value {
+                Value::String(string) => string.clone(),
+                value => value.to_string(),
+            }),
+        };
+        match value {
+            Some(value) => text.push_str(&value),
+            None => text.push_str(&rest[start..=start + end]),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    text.push_str(rest);
+    text
+}
+
+/// `http://` or `https://` URL of a webhook
+#[derive(Debug, PartialEq)]
+struct Webhook<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Webhook<'a> {
+    fn parse(url: &'a str) -> anyhow::Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            bail!("webhook `{url}` must start with http:// or https://");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        // an IPv6 address is written in brackets, `[::1]:8080`
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => bail!("bad port in webhook `{url}`"),
+                },
+                None => bail!("unclosed `[` in webhook `{url}`"),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("bad port in webhook `{url}`"))?,
+            None if tls => 443,
+            None => 80,
+        };
+        if host.is_empty() {
+            bail!("no host in webhook `{url}`");
+        }
+        Ok(Self {
+            tls,
+            host,
+            port,
+            path,
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> anyhow::Result<()> {
+        let stream = self.connect()?;
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+        if self.tls {
+            let connector = SslConnector::builder(SslMethod::tls())?.build();
+            self.exchange(connector.connect(self.host, stream)?, body)
+        } else {
+            self.exchange(stream, body)
+        }
+    }
+
+    /// Tries every address of the host, each for at most [`WEBHOOK_TIMEOUT`]
+    fn connect(&self) -> anyhow::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in (self.host, self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        match last_error {
+            Some(error) => Err(error.into()),
+            None => bail!("no address for {}", self.host),
+        }
+    }
+
+    fn exchange(&self, mut stream: impl Read + Write, body: &[u8]) -> anyhow::Result<()> {
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            if self.host.contains(':') {
+                format!("[{}]", self.host)
+            } else {
+                self.host.to_string()
+            },
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        let mut response = [0u8; 64];
+        let count = stream.read(&mut response)?;
+        let status_line = String::from_utf8_lossy(&response[..count]);
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            bail!(
+                "answered `{}`",
+                status_line.lines().next().unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier() -> Notifier {
+        let config: NotifyConfig = toml::from_str(
+            r#"
+            [[rule]]
+            name = "battery low"
+            field = "u_acc"
+            condition = "below"
+            threshold = 46.0
+            hysteresis = 1.0
+            cooldown = "30min"
+            message = "{unit}: {rule} at {value} V, {mode}, errors: {errors}"
+            "#,
+        )
+        .unwrap();
+        Notifier::new(config, &["u_acc".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn fires_with_hysteresis_and_cooldown() {
+        let mut notifier = notifier();
+        let sample = |u_acc| MapInfo {
+            u_acc,
+            rs_err_job: 3,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        assert!(notifier.check("1", &sample(48.0), at(0)).is_empty());
+        let fired = notifier.check("1", &sample(45.3), at(1));
+        assert_eq!(
+            fired[0].message,
+            "1: battery low at 45.3 V, Off, no grid, errors: rs_err_job=3"
+        );
+        assert_eq!(fired[0].value.to_string(), "45.3");
+        // within the hysteresis the alarm stays on
+        assert!(notifier.check("1", &sample(46.5), at(2)).is_empty());
+        assert!(notifier.check("1", &sample(45.0), at(3)).is_empty());
+        // cleared, but still cooling down
+        assert!(notifier.check("1", &sample(47.5), at(4)).is_empty());
+        assert!(notifier.check("1", &sample(45.0), at(5)).is_empty());
+        assert!(notifier.check("1", &sample(47.5), at(40)).is_empty());
+        assert_eq!(notifier.check("1", &sample(45.0), at(41)).len(), 1);
+        // every unit has its own state
+        assert_eq!(notifier.check("2", &sample(45.0), at(41)).len(), 1);
+        // started during the cooldown, sent once it ends if still on
+        assert!(notifier.check("1", &sample(47.5), at(42)).is_empty());
+        assert!(notifier.check("1", &sample(45.0), at(43)).is_empty());
+        assert!(notifier.check("1", &sample(45.0), at(70)).is_empty());
+        assert_eq!(notifier.check("1", &sample(45.0), at(71)).len(), 1);
+        assert!(notifier.check("1", &sample(45.0), at(72)).is_empty());
+        // or dropped if cleared before
+        assert!(notifier.check("2", &sample(47.5), at(42)).is_empty());
+        assert!(notifier.check("2", &sample(45.0), at(43)).is_empty());
+        assert!(notifier.check("2", &sample(47.5), at(44)).is_empty());
+        assert!(notifier.check("2", &sample(47.5), at(80)).is_empty());
+    }
+
+    #[test]
+    fn leaves_unknown_and_unclosed_placeholders_alone() {
+        let notification = Notification {
+            timestamp: String::new(),
+            unit: "1".to_string(),
+            rule: "battery low".to_string(),
+            field: "u_acc".to_string(),
+            value: 45.3,
+            threshold: 46.0,
+            mode: "Off",
+            errors: BTreeMap::new(),
+            message: String::new(),
+        };
+        let fields = MapInfo::default().fields().unwrap();
+        let render = |template| render(template, &notification, &fields);
+        assert_eq!(render("a {b"), "a {b");
+        assert_eq!(render("{unit} {nope} {"), "1 {nope} {");
+        assert_eq!(render("{rule}: {errors}"), "battery low: none");
+    }
+
+    #[test]
+    fn parses_webhooks_and_rejects_unknown_fields() {
+        let webhook = Webhook::parse("https://example.com:8443/hooks/map").unwrap();
+        assert_eq!(
+            webhook,
+            Webhook {
+                tls: true,
+                host: "example.com",
+                port: 8443,
+                path: "/hooks/map",
+            }
+        );
+        assert_eq!(Webhook::parse("http://example.com").unwrap().port, 80);
+        let webhook = Webhook::parse("http://[::1]:8080/map").unwrap();
+        assert_eq!((webhook.host, webhook.port), ("::1", 8080));
+        assert_eq!(Webhook::parse("https://[fe80::1]").unwrap().port, 443);
+        assert!(Webhook::parse("http://[::1/map").is_err());
+        assert!(Webhook::parse("http://[::1]8080").is_err());
+        assert!(Webhook::parse("ftp://example.com").is_err());
+
+        let config: NotifyConfig = toml::from_str(
+            r#"
+            [[rule]]
+            name = "typo"
+            field = "u_ac"
+            condition = "above"
+            threshold = 1
+            "#,
+        )
+        .unwrap();
+        assert!(Notifier::new(config, &["u_acc".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    aggregate::Aggregate,
+    map_protocol::{high_level::MapInfo, registers::RegisterMap},
+};
+
+/// Latest sample of every unit in the Prometheus text format: a `map_<register>`
+/// gauge, or a counter for `total_increasing` registers, labelled with the unit id
+pub fn render(registers: &RegisterMap, latest: &BTreeMap<String, MapInfo>) -> String {
+    // through the JSON text, so f32 fields keep the value the MAP reported
+    let samples: Vec<(&String, serde_json::Map<String, serde_json::Value>)> = latest
+        .iter()
+        .map(|(id, map_info)| (id, map_info.fields().unwrap_or_default()))
+        .collect();
+    let mut text = String::new();
+    for register in registers.iter().filter(|register| register.is_status()) {
+        let values: Vec<(&String, f64)> = samples
+            .iter()
+            .filter_map(|(id, sample)| Some((*id, sample.get(&register.name)?.as_f64()?)))
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        let name = format!("map_{}", register.name);
+        let kind = match register.state_class.as_deref() {
+            Some("total_increasing") => "counter",
+            _ => "gauge",
+        };
+        let help = match &register.unit {
+            Some(unit) => format!("{}, {unit}", register.description),
+            None => register.description.clone(),
+        };
+        let _ = writeln!(text, "# HELP {name} {}", help.replace('\n', " "));
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        for (id, value) in values {
+            let _ = writeln!(
+                text,
+                "{name}{{unit=\"{}\"}} {value}",
+                id.replace('"', "\\\"")
+            );
+        }
+    }
+    if !latest.is_empty() {
+        let _ = writeln!(
+            text,
+            "# HELP map_firmware_info Firmware detected when the MAP was identified"
+        );
+        let _ = writeln!(text, "# TYPE map_firmware_info gauge");
+    }
+    for (id, map_info) in latest {
+        let firmware = &map_info.firmware;
+        let mut labels = format!(
+            "unit=\"{}\",layout=\"{}\"",
+            id.replace('"', "\\\""),
+            firmware.layout
+        );
+        if let Some(version) = firmware.hardware_version {
+            let _ = write!(labels, ",hardware=\"{version}\"");
+        }
+        if let Some(version) = firmware.firmware_version {
+            let _ = write!(labels, ",firmware=\"{version}\"");
+        }
+        let _ = writeln!(text, "map_firmware_info{{{labels}}} 1");
+    }
+    text
+}
+
+/// Serves `GET /metrics` on a thread of its own, scrapes are rare and tiny
+pub fn serve(
+    listener: TcpListener,
+    registers: Arc<RegisterMap>,
+    aggregate: Arc<Mutex<Aggregate>>,
+) -> io::Result<()> {
+    info!("serving Prometheus metrics at {}", listener.local_addr()?);
+    thread::Builder::new()
+        .name("prometheus".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    let body = {
+                        let mut aggregate = aggregate.lock().unwrap();
+                        aggregate.expire(Instant::now());
+                        render(&registers, aggregate.latest())
+                    };
+                    respond(stream, &body)
+                });
+                if let Err(error) = result {
+                    warn!("cannot serve metrics: {}", error);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0u8; 1024];
+    let count = stream.read(&mut request)?;
+    let (status, body) = if request[..count].starts_with(b"GET /metrics ") {
+        ("200 OK", metrics)
+    } else {
+        ("404 Not Found", "not found\n")
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_gauges_and_counters_per_unit() {
+        let mut latest = BTreeMap::new();
+        latest.insert(
+            "1".to_string(),
+            MapInfo {
+                u_acc: 45.3,
+                e_net: 1234,
+                ..Default::default()
+            },
+        );
+        latest.insert("2".to_string(), MapInfo::default());
+        let text = render(&RegisterMap::builtin(), &latest);
+        assert!(text.contains("# TYPE map_u_acc gauge\n"));
+        assert!(text.contains("map_u_acc{unit=\"1\"} 45.3\n"));
+        assert!(text.contains("map_u_acc{unit=\"2\"} 0\n"));
+        assert!(text.contains("# TYPE map_e_net counter\n"));
+        assert!(text.contains("map_e_net{unit=\"1\"} 1234\n"));
+        assert!(text.contains("map_firmware_info{unit=\"2\",layout=\"unknown\"} 1\n"));
+        // settings and non-numeric fields are left out
+        assert!(!text.contains("map_phase_config"));
+        assert!(!text.contains("map_mode{"));
+        assert!(!text.contains("map_mode_label"));
+        assert!(text.contains("map_mode_raw{unit=\"1\"} 0\n"));
+    }
+}
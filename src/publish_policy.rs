@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// How far a numeric field has to move away from the last published value
+/// before the change is considered significant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deadband {
+    /// Absolute difference in the units of the field
+    Absolute(f64),
+    /// Difference relative to the last published value, in percent
+    Percent(f64),
+}
+
+impl Deadband {
+    fn exceeded(&self, published: f64, current: f64) -> bool {
+        let delta = (current - published).abs();
+        match self {
+            Deadband::Absolute(threshold) => delta > *threshold,
+            Deadband::Percent(percent) => {
+                if published == 0.0 {
+                    delta > 0.0
+                } else {
+                    delta * 100.0 / published.abs() > *percent
+                }
+            }
+        }
+    }
+}
+
+/// `field=threshold` pair as given on the command line, e.g. `u_acc=0.5` or `p_load=10%`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDeadband {
+    pub field: String,
+    pub deadband: Deadband,
+}
+
+impl FromStr for FieldDeadband {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, threshold) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected FIELD=THRESHOLD, got `{s}`"))?;
+        let field = field.trim();
+        let threshold = threshold.trim();
+        if field.is_empty() {
+            bail!("field name is empty in `{s}`");
+        }
+        let deadband = match threshold.strip_suffix('%') {
+            Some(percent) => Deadband::Percent(percent.trim().parse()?),
+            None => Deadband::Absolute(threshold.parse()?),
+        };
+        match deadband {
+            Deadband::Absolute(v) | Deadband::Percent(v) if v < 0.0 || !v.is_finite() => {
+                bail!("threshold must be a non-negative number in `{s}`")
+            }
+            _ => {}
+        }
+        Ok(Self {
+            field: field.into(),
+            deadband,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishReason {
+    /// Nothing was published yet
+    First,
+    /// At least one field moved beyond its deadband
+    Changed,
+    /// Nothing changed significantly but `max_interval` elapsed
+    Heartbeat,
+}
+
+impl Display for PublishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublishReason::First => write!(f, "first"),
+            PublishReason::Changed => write!(f, "changed"),
+            PublishReason::Heartbeat => write!(f, "heartbeat"),
+        }
+    }
+}
+
+/// Decides whether a freshly read sample is worth publishing.
+///
+/// Samples are compared field by field with the last *published* sample, so a
+/// slow drift accumulates until it crosses the deadband instead of being
+/// swallowed poll after poll. Fields without a deadband are compared exactly.
+#[derive(Debug)]
+pub struct PublishPolicy {
+    deadbands: HashMap<String, Deadband>,
+    min_interval: Duration,
+    max_interval: Duration,
+    last_published: Option<(Instant, Map<String, Value>)>,
+}
+
+impl PublishPolicy {
+    /// `known_fields` are the names deadbands may be set for
+    pub fn new(
+        deadbands: &[FieldDeadband],
+        min_interval: Duration,
+        max_interval: Duration,
+        known_fields: &[String],
+    ) -> anyhow::Result<Self> {
+        if max_interval < min_interval {
+            bail!("maximum publish interval is shorter than the minimum one");
+        }
+        let mut map = HashMap::new();
+        for FieldDeadband { field, deadband } in deadbands {
+            if !known_fields.contains(field) {
+                bail!("unknown field `{field}` in deadband");
+            }
+            map.insert(field.clone(), *deadband);
+        }
+        Ok(Self {
+            deadbands: map,
+            min_interval,
+            max_interval,
+            last_published: None,
+        })
+    }
+
+    /// Returns why `sample` has to be published now, or `None` to skip it.
+    /// The caller must call [`PublishPolicy::published`] once the sample is actually sent.
+    pub fn check<T: Serialize>(
+        &self,
+        sample: &T,
+        now: Instant,
+    ) -> anyhow::Result<Option<PublishReason>> {
+        let Some((published_at, published)) = &self.last_published else {
+            return Ok(Some(PublishReason::First));
+        };
+        let elapsed = now.saturating_duration_since(*published_at);
+        if elapsed >= self.max_interval {
+            return Ok(Some(PublishReason::Heartbeat));
+        }
+        if elapsed < self.min_interval {
+            return Ok(None);
+        }
+        let sample = to_object(sample)?;
+        let changed = sample
+            .iter()
+            .any(|(field, value)| self.field_changed(field, published.get(field), value));
+        Ok(changed.then_some(PublishReason::Changed))
+    }
+
+    pub fn published<T: Serialize>(&mut self, sample: &T, now: Instant) -> anyhow::Result<()> {
+        self.last_published = Some((now, to_object(sample)?));
+        Ok(())
+    }
+
+    fn field_changed(&self, field: &str, published: Option<&Value>, current: &Value) -> bool {
+        let Some(published) = published else {
+            return true;
+        };
+        match (
+            self.deadbands.get(field),
+            published.as_f64(),
+            current.as_f64(),
+        ) {
+            (Some(deadband), Some(published), Some(current)) => {
+                deadband.exceeded(published, current)
+            }
+            _ => published != current,
+        }
+    }
+}
+
+fn to_object<T: Serialize>(sample: &T) -> anyhow::Result<Map<String, Value>> {
+    match serde_json::to_value(sample)? {
+        Value::Object(map) => Ok(map),
+        other => bail!("expected an object, got {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(deadbands: &[&str], min: u64, max: u64) -> PublishPolicy {
+        let deadbands: Vec<FieldDeadband> = deadbands.iter().map(|d| d.parse().unwrap()).collect();
+        PublishPolicy::new(
+            &deadbands,
+            Duration::from_secs(min),
+            Duration::from_secs(max),
+            &[
+                "u_acc".to_string(),
+                "p_load".to_string(),
+                "mode".to_string(),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_deadbands() {
+        assert_eq!(
+            "u_acc=0.5".parse::<FieldDeadband>().unwrap().deadband,
+            Deadband::Absolute(0.5)
+        );
+        assert_eq!(
+            "p_load=10%".parse::<FieldDeadband>().unwrap().deadband,
+            Deadband::Percent(10.0)
+        );
+        assert!("u_acc".parse::<FieldDeadband>().is_err());
+        assert!("u_acc=-1".parse::<FieldDeadband>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let deadbands = ["nope=1".parse().unwrap()];
+        assert!(PublishPolicy::new(
+            &deadbands,
+            Duration::ZERO,
+            Duration::from_secs(60),
+            &["u_acc".to_string()]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deadbands_are_measured_from_last_published_value() {
+        let mut policy = policy(&["u_acc=0.5", "p_load=10%"], 0, 300);
+        let t0 = Instant::now();
+        let first = json!({"u_acc": 52.0, "p_load": 1000, "mode": "PowerOff"});
+        assert_eq!(
+            policy.check(&first, t0).unwrap(),
+            Some(PublishReason::First)
+        );
+        policy.published(&first, t0).unwrap();
+
+        let t1 = t0 + Duration::from_secs(10);
+        let wobble = json!({"u_acc": 52.3, "p_load": 1050, "mode": "PowerOff"});
+        assert_eq!(policy.check(&wobble, t1).unwrap(), None);
+
+        let drift = json!({"u_acc": 52.6, "p_load": 1050, "mode": "PowerOff"});
+        assert_eq!(
+            policy.check(&drift, t1).unwrap(),
+            Some(PublishReason::Changed)
+        );
+
+        let mode = json!({"u_acc": 52.0, "p_load": 1000, "mode": "PowerOffExternalPowerPresent"});
+        assert_eq!(
+            policy.check(&mode, t1).unwrap(),
+            Some(PublishReason::Changed)
+        );
+    }
+
+    #[test]
+    fn intervals_rate_limit_and_force_heartbeat() {
+        let mut policy = policy(&[], 30, 300);
+        let t0 = Instant::now();
+        let sample = json!({"u_acc": 52.0, "p_load": 1000, "mode": "PowerOff"});
+        policy.published(&sample, t0).unwrap();
+
+        let changed = json!({"u_acc": 53.0, "p_load": 1000, "mode": "PowerOff"});
+        assert_eq!(
+            policy
+                .check(&changed, t0 + Duration::from_secs(10))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            policy
+                .check(&changed, t0 + Duration::from_secs(30))
+                .unwrap(),
+            Some(PublishReason::Changed)
+        );
+        assert_eq!(
+            policy
+                .check(&sample, t0 + Duration::from_secs(299))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            policy
+                .check(&sample, t0 + Duration::from_secs(300))
+                .unwrap(),
+            Some(PublishReason::Heartbeat)
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use chrono::Local;
+use log::warn;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    hexdump::{hexdump, hexdump_changes, spaced},
+    map_actor::MapHandle,
+    map_protocol::registers::RegisterMap,
+};
+
+/// Address or length given as decimal or `0x` prefixed hex
+pub fn parse_number(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|error| format!("`{s}` is not a 16 bit number: {error}"))
+}
+
+/// Bytes given as hex, e.g. `01 ff 20`, `01,ff,20` or `01ff20`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl FromStr for HexBytes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ',')
+            .collect();
+        let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+        if digits.is_empty() || !digits.len().is_multiple_of(2) {
+            bail!("expected an even number of hex digits in `{s}`");
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&digits[index..index + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|error| anyhow!("`{s}` is not hex: {error}"))?;
+        Ok(Self(bytes))
+    }
+}
+
+fn check_range(addr: u16, len: usize) -> anyhow::Result<()> {
+    if len == 0 || addr as usize + len > 0x10000 {
+        bail!("range {addr:#06x} + {len:#x} does not fit the 16 bit address space");
+    }
+    Ok(())
+}
+
+/// Known registers lying in the range with their raw bytes and, when they are
+/// completely in the range, the decoded value
+fn print_registers(
+    registers: &RegisterMap,
+    addr: u16,
+    memory: &[u8],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for register in registers.in_range(addr, memory.len() as u16) {
+        let Some(register_addr) = register.addr else {
+            continue;
+        };
+        let start = register_addr.saturating_sub(addr) as usize;
+        let end =
+            (register_addr as usize + register.width as usize - addr as usize).min(memory.len());
+        let bytes = &memory[start..end];
+        write!(
+            out,
+            "    {:04x}  {:<20} {:<12}",
+            register_addr,
+            register.name,
+            spaced(bytes)
+        )?;
+        if register_addr >= addr && bytes.len() == register.width as usize {
+            write!(out, " {}", register.decode(bytes))?;
+            if let Some(unit) = &register.unit {
+                write!(out, " {unit}")?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub async fn read(
+    map: &MapHandle,
+    addr: u16,
+    len: u16,
+    decode: Option<&RegisterMap>,
+) -> anyhow::Result<()> {
+    check_range(addr, len as usize)?;
+    let memory = map.read_memory(addr, len).await?;
+    let mut out = io::stdout().lock();
+    hexdump(addr, &memory, &mut out)?;
+    if let Some(registers) = decode {
+        writeln!(out)?;
+        print_registers(registers, addr, &memory, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Polls the range until Ctrl-C and prints it whenever it changes, with the changed bytes highlighted
+pub async fn watch(
+    map: &MapHandle,
+    addr: u16,
+    len: u16,
+    decode: Option<&RegisterMap>,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    check_range(addr, len as usize)?;
+    // registered once, so Ctrl-C during a slow read is not lost
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut previous: Option<Vec<u8>> = None;
+    loop {
+        let memory = tokio::select! {
+            memory = map.read_memory(addr, len) => memory,
+            _ = interrupt.recv() => return Ok(()),
+        };
+        match memory {
+            Ok(memory) if previous.as_ref() != Some(&memory) => {
+                let mut out = io::stdout().lock();
+                writeln!(out, "{}", Local::now().format("%H:%M:%S%.3f"))?;
+                hexdump_changes(addr, &memory, previous.as_deref(), &mut out)?;
+                if let Some(registers) = decode {
+                    print_registers(registers, addr, &memory, &mut out)?;
+                }
+                writeln!(out)?;
+                previous = Some(memory);
+            }
+            Ok(_) => {}
+            Err(error) => warn!("cannot read memory: {:#}", error),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = interrupt.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Writes the bytes and dumps what is read back from the same range
+pub async fn write(map: &MapHandle, addr: u16, bytes: Vec<u8>, force: bool) -> anyhow::Result<()> {
+    check_range(addr, bytes.len())?;
+    let len = bytes.len() as u16;
+    map.write_memory(addr, bytes.clone(), force).await?;
+    let memory = map.read_memory(addr, len).await?;
+    hexdump(addr, &memory, &mut io::stdout().lock())?;
+    if memory != bytes {
+        bail!("memory read back differs from the bytes written");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numbers_and_bytes() {
+        assert_eq!(parse_number("0x400"), Ok(0x400));
+        assert_eq!(parse_number("256"), Ok(256));
+        assert!(parse_number("0x10000").is_err());
+        assert_eq!(
+            "01 ff 20".parse::<HexBytes>().unwrap(),
+            HexBytes(vec![1, 0xff, 0x20])
+        );
+        assert_eq!("01ff".parse::<HexBytes>().unwrap(), HexBytes(vec![1, 0xff]));
+        assert!("1ff".parse::<HexBytes>().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::future::Future;
+
+use log::debug;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, watch},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGTERM, SIGINT or SIGQUIT
+    Shutdown(&'static str),
+    /// SIGHUP
+    Reload,
+}
+
+/// Sends [`Signal`]s to every subscriber. A shutdown stays pending, so tasks
+/// subscribing after it still see it.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    shutdown: watch::Sender<Option<&'static str>>,
+    reload: broadcast::Sender<()>,
+}
+
+impl Signals {
+    pub fn subscribe(&self) -> SignalReceiver {
+        SignalReceiver {
+            shutdown: self.shutdown.subscribe(),
+            reload: self.reload.subscribe(),
+        }
+    }
+
+    pub fn send(&self, signal: Signal) {
+        match signal {
+            Signal::Shutdown(name) => {
+                // the first shutdown wins, later ones would only rename it
+                self.shutdown.send_if_modified(|pending| {
+                    let first = pending.is_none();
+                    pending.get_or_insert(name);
+                    first
+                });
+            }
+            // nobody listening yet is not an error, a reload has nothing to redo then
+            Signal::Reload => {
+                let _ = self.reload.send(());
+            }
+        }
+    }
+}
+
+pub struct SignalReceiver {
+    shutdown: watch::Receiver<Option<&'static str>>,
+    reload: broadcast::Receiver<()>,
+}
+
+impl SignalReceiver {
+    /// The next signal, a pending shutdown first. `None` once the sender is gone.
+    pub async fn recv(&mut self) -> Option<Signal> {
+        loop {
+            if let Some(name) = *self.shutdown.borrow_and_update() {
+                return Some(Signal::Shutdown(name));
+            }
+            tokio::select! {
+                changed = self.shutdown.changed() => changed.ok()?,
+                reload = self.reload.recv() => match reload {
+                    Ok(()) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                        return Some(Signal::Reload)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            }
+        }
+    }
+
+    /// Runs `future` to completion unless a shutdown comes first, whose name is returned then
+    pub async fn unless_shutdown<T>(
+        &mut self,
+        future: impl Future<Output = T>,
+    ) -> Result<T, &'static str> {
+        let shutdown = async {
+            loop {
+                match self.recv().await {
+                    Some(Signal::Shutdown(name)) => break name,
+                    Some(Signal::Reload) => {}
+                    None => std::future::pending().await,
+                }
+            }
+        };
+        tokio::select! {
+            output = future => Ok(output),
+            name = shutdown => Err(name),
+        }
+    }
+}
+
+/// Starts a task translating process signals into [`Signal`]s.
+///
+/// Polling tasks pick them up between MAP transactions, so a command is never
+/// cut in the middle of a frame. [`Signals::send`] can also be used to stop
+/// them from inside the bridge.
+pub fn listen() -> anyhow::Result<Signals> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let signals = Signals {
+        shutdown: watch::Sender::new(None),
+        reload: broadcast::channel(8).0,
+    };
+    let sender = signals.clone();
+    tokio::spawn(async move {
+        loop {
+            let signal = tokio::select! {
+                _ = sigterm.recv() => Signal::Shutdown("SIGTERM"),
+                _ = sigint.recv() => Signal::Shutdown("SIGINT"),
+                _ = sigquit.recv() => Signal::Shutdown("SIGQUIT"),
+                _ = sighup.recv() => Signal::Reload,
+            };
+            debug!("got signal {:?}", signal);
+            sender.send(signal);
+        }
+    });
+    Ok(signals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn late_subscriber_sees_pending_shutdown() {
+        let signals = Signals {
+            shutdown: watch::Sender::new(None),
+            reload: broadcast::channel(8).0,
+        };
+        let mut early = signals.subscribe();
+        signals.send(Signal::Reload);
+        signals.send(Signal::Shutdown("SIGTERM"));
+        signals.send(Signal::Shutdown("SIGINT"));
+        assert_eq!(early.recv().await, Some(Signal::Shutdown("SIGTERM")));
+
+        let mut late = signals.subscribe();
+        assert_eq!(late.recv().await, Some(Signal::Shutdown("SIGTERM")));
+        let pending = late.unless_shutdown(std::future::pending::<()>()).await;
+        assert_eq!(pending, Err("SIGTERM"));
+    }
+}
@@ -0,0 +1,424 @@
+//! Battery state of charge, which the MAP does not report.
+//!
+//! `battery_current` is integrated between samples (coulomb counting), charge
+//! with the efficiency of the chemistry, against the capacity corrected for the
+//! battery temperature `temp_grad0`. Counting drifts, so the estimate is set
+//! again from the voltage:
+//!
+//! * to 100% when the cell voltage reached the end of charge while the current
+//!   fell to a tail of 2% of the capacity, for [`FULL_HOLD`]
+//! * from the open circuit voltage after [`REST_HOLD`] with almost no current,
+//!   except for LiFePO4 whose voltage is flat over most of the range
+//!
+//! Without a saved estimate the first sample is taken from the open circuit
+//! voltage table, however loaded the battery is.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{map_protocol::high_level::MapInfo, state::StateFile};
+
+/// Longer pauses between samples are not integrated
+const MAX_GAP: Duration = Duration::from_secs(600);
+const FULL_HOLD: Duration = Duration::from_secs(300);
+const REST_HOLD: Duration = Duration::from_secs(1800);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Chemistry {
+    LeadAcid,
+    Lifepo4,
+    LiIon,
+}
+
+impl Chemistry {
+    /// Nominal cell voltage
+    fn cell_voltage(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => 2.0,
+            Chemistry::Lifepo4 => 3.2,
+            Chemistry::LiIon => 3.7,
+        }
+    }
+
+    /// Open circuit cell voltage at 0, 10, ..., 100% and 25 °C
+    fn ocv(self) -> [f32; 11] {
+        match self {
+            Chemistry::LeadAcid => [
+                1.885, 1.918, 1.943, 1.968, 1.993, 2.017, 2.040, 2.062, 2.083, 2.103, 2.122,
+            ],
+            Chemistry::Lifepo4 => [
+                2.50, 3.20, 3.25, 3.28, 3.29, 3.30, 3.31, 3.32, 3.33, 3.35, 3.40,
+            ],
+            Chemistry::LiIon => [
+                3.00, 3.45, 3.55, 3.62, 3.68, 3.74, 3.80, 3.87, 3.95, 4.05, 4.15,
+            ],
+        }
+    }
+
+    /// Cell voltage of an empty battery under load
+    fn empty_voltage(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => 1.75,
+            Chemistry::Lifepo4 => 2.50,
+            Chemistry::LiIon => 3.00,
+        }
+    }
+
+    /// Cell voltage at the end of charge, 25 °C
+    fn full_voltage(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => 2.35,
+            Chemistry::Lifepo4 => 3.45,
+            Chemistry::LiIon => 4.10,
+        }
+    }
+
+    /// Change of the end of charge voltage per °C, per cell
+    fn full_voltage_coefficient(self) -> f32 {
+        match self {
+            Chemistry::LeadAcid => -0.003,
+            _ => 0.0,
+        }
+    }
+
+    /// Change of the capacity per °C, relative
+    fn capacity_coefficient(self) -> f64 {
+        match self {
+            Chemistry::LeadAcid => 0.006,
+            _ => 0.003,
+        }
+    }
+
+    fn charge_efficiency(self) -> f64 {
+        match self {
+            Chemistry::LeadAcid => 0.9,
+            Chemistry::Lifepo4 => 0.99,
+            Chemistry::LiIon => 0.98,
+        }
+    }
+
+    fn rest_resync(self) -> bool {
+        self != Chemistry::Lifepo4
+    }
+
+    /// Cells in series, by default the nominal voltage of the MAP over the cell voltage
+    pub fn cells(self, cells: Option<u32>, nominal_voltage: f32) -> u32 {
+        cells
+            .unwrap_or((nominal_voltage / self.cell_voltage()).round() as u32)
+            .max(1)
+    }
+
+    /// Where the voltage of `cells` in series lies between empty and the end of
+    /// charge, 0.0..=1.0. Not a state of charge, the voltage sags under load
+    pub fn voltage_level(self, voltage: f32, cells: u32) -> f32 {
+        let empty = self.empty_voltage();
+        let cell_voltage = voltage / cells as f32;
+        ((cell_voltage - empty) / (self.full_voltage() - empty)).clamp(0.0, 1.0)
+    }
+}
+
+/// Battery settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Battery {
+    pub capacity_ah: f64,
+    pub chemistry: Chemistry,
+    pub cells: u32,
+}
+
+impl Battery {
+    /// Cells in series default to [`Chemistry::cells`]
+    pub fn new(
+        capacity_ah: f64,
+        chemistry: Chemistry,
+        cells: Option<u32>,
+        nominal_voltage: f32,
+    ) -> Self {
+        Self {
+            capacity_ah,
+            chemistry,
+            cells: chemistry.cells(cells, nominal_voltage),
+        }
+    }
+
+    /// Open circuit state of charge, 0.0..=1.0
+    fn ocv_soc(&self, cell_voltage: f32) -> f64 {
+        let table = self.chemistry.ocv();
+        if cell_voltage <= table[0] {
+            return 0.0;
+        }
+        for (index, pair) in table.windows(2).enumerate() {
+            if cell_voltage <= pair[1] {
+                let fraction = (cell_voltage - pair[0]) / (pair[1] - pair[0]);
+                return (index as f64 + f64::from(fraction)) / 10.0;
+            }
+        }
+        1.0
+    }
+
+    /// Capacity at `temperature`, at most 10% above the rated one
+    fn capacity(&self, temperature: f32) -> f64 {
+        let factor = 1.0 + self.chemistry.capacity_coefficient() * f64::from(temperature - 25.0);
+        self.capacity_ah * factor.clamp(0.3, 1.1)
+    }
+}
+
+/// Payload of the retained `<topic>/soc` message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SocReport {
+    pub soc_percent: f64,
+    /// Hours until empty at the present discharge current
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_to_empty_h: Option<f64>,
+    /// Hours until full at the present charge current
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_to_full_h: Option<f64>,
+    /// Capacity at the present battery temperature
+    pub capacity_ah: f64,
+}
+
+/// State of charge of one MAP
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Estimator {
+    /// 0.0..=1.0, `None` before the first sample
+    soc: Option<f64>,
+    #[serde(skip)]
+    last_sample: Option<DateTime<Local>>,
+    #[serde(skip)]
+    full_since: Option<DateTime<Local>>,
+    #[serde(skip)]
+    rest_since: Option<DateTime<Local>>,
+}
+
+impl Estimator {
+    pub fn update(
+        &mut self,
+        battery: &Battery,
+        sample: &MapInfo,
+        now: DateTime<Local>,
+    ) -> SocReport {
+        // -50 °C is what a missing sensor reads
+        let temperature = match sample.temp_grad0 {
+            -40..=80 => f32::from(sample.temp_grad0),
+            _ => 25.0,
+        };
+        let capacity = battery.capacity(temperature);
+        let cell_voltage = sample.u_acc / battery.cells as f32;
+        let current = f64::from(sample.battery_current);
+        let efficiency = battery.chemistry.charge_efficiency();
+
+        let hours = self
+            .last_sample
+            .and_then(|last| (now - last).to_std().ok())
+            .filter(|elapsed| *elapsed <= MAX_GAP)
+            .map(|elapsed| elapsed.as_secs_f64() / 3600.0);
+        self.last_sample = Some(now);
+        let mut soc = match (self.soc, hours) {
+            (None, _) => battery.ocv_soc(cell_voltage),
+            (Some(soc), None) => soc,
+            (Some(soc), Some(hours)) => {
+                let charge = if current > 0.0 {
+                    current * efficiency
+                } else {
+                    current
+                };
+                (soc + charge * hours / capacity).clamp(0.0, 1.0)
+            }
+        };
+
+        let full_voltage = battery.chemistry.full_voltage()
+            + battery.chemistry.full_voltage_coefficient() * (temperature - 25.0);
+        let tail = current >= 0.0 && current < 0.02 * battery.capacity_ah;
+        if cell_voltage >= full_voltage && tail {
+            let since = *self.full_since.get_or_insert(now);
+            if held(since, now, FULL_HOLD) {
+                soc = 1.0;
+            }
+        } else {
+            self.full_since = None;
+        }
+        if current.abs() < 0.01 * battery.capacity_ah && battery.chemistry.rest_resync() {
+            let since = *self.rest_since.get_or_insert(now);
+            if held(since, now, REST_HOLD) {
+                soc = battery.ocv_soc(cell_voltage);
+            }
+        } else {
+            self.rest_since = None;
+        }
+        self.soc = Some(soc);
+
+        let significant = 0.01 * battery.capacity_ah;
+        SocReport {
+            soc_percent: soc * 100.0,
+            time_to_empty_h: (current < -significant).then(|| soc * capacity / -current),
+            time_to_full_h: (current > significant)
+                .then(|| (1.0 - soc) * capacity / (current * efficiency)),
+            capacity_ah: capacity,
+        }
+    }
+}
+
+fn held(since: DateTime<Local>, now: DateTime<Local>, hold: Duration) -> bool {
+    (now - since).to_std().is_ok_and(|elapsed| elapsed >= hold)
+}
+
+/// Estimators of every MAP, saved to a JSON file when one is given
+#[derive(Debug, Default)]
+pub struct SocLedger {
+    state: Option<StateFile>,
+    units: BTreeMap<String, Estimator>,
+}
+
+impl SocLedger {
+    /// Reads the estimates saved at `path`, a missing file starts from the voltage
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let state = path.map(|path| StateFile::new(path, "SoC state"));
+        let units = match &state {
+            Some(state) => state.load()?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self { state, units })
+    }
+
+    /// Adds a sample and saves the estimates, returns the report of the unit
+    pub fn update(
+        &mut self,
+        id: &str,
+        battery: &Battery,
+        sample: &MapInfo,
+        now: DateTime<Local>,
+    ) -> SocReport {
+        let report = self
+            .units
+            .entry(id.to_string())
+            .or_default()
+            .update(battery, sample, now);
+        if let Some(state) = &mut self.state {
+            state.changed(&self.units, Instant::now());
+        }
+        report
+    }
+
+    /// Saves estimates not saved yet, on shutdown
+    pub fn flush(&mut self) {
+        if let Some(state) = &mut self.state {
+            state.flush(&self.units);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn sample(u_acc: f32, battery_current: f32) -> MapInfo {
+        MapInfo {
+            u_acc,
+            battery_current,
+            temp_grad0: 25,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_charge_and_resyncs_when_full() {
+        let battery = Battery::new(100.0, Chemistry::LeadAcid, None, 24.0);
+        assert_eq!(battery.cells, 12);
+        let at = |minute| Local.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let mut estimator = Estimator::default();
+        // 12.10 V per 12 V is half charged
+        let report = estimator.update(&battery, &sample(24.2, -10.0), at(0));
+        assert!((report.soc_percent - 50.0).abs() < 0.5);
+        // 10 A for 6 minutes is 1 Ah, 1% of the capacity
+        let report = estimator.update(&battery, &sample(24.2, -10.0), at(6));
+        assert!((report.soc_percent - 49.0).abs() < 0.5);
+        let hours = report.time_to_empty_h.unwrap();
+        assert!((hours - 4.9).abs() < 0.1);
+
+        // absorption voltage with a tail current, held for 5 minutes
+        estimator.update(&battery, &sample(28.4, 1.0), at(10));
+        let report = estimator.update(&battery, &sample(28.4, 1.0), at(13));
+        assert!(report.soc_percent < 100.0);
+        let report = estimator.update(&battery, &sample(28.4, 1.0), at(16));
+        assert_eq!(report.soc_percent, 100.0);
+        assert!(report.time_to_full_h.is_none());
+    }
+
+    #[test]
+    fn resyncs_from_the_rest_voltage_except_lifepo4() {
+        let at = |minute| Local.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let lead_acid = Battery::new(100.0, Chemistry::LeadAcid, None, 24.0);
+        let mut estimator = Estimator::default();
+        estimator.update(&lead_acid, &sample(24.2, 0.0), at(0));
+        // 2.05 V per cell is 64.5%, once rested for 30 minutes
+        let report = estimator.update(&lead_acid, &sample(24.6, 0.0), at(20));
+        assert!((report.soc_percent - 50.0).abs() < 0.5);
+        let report = estimator.update(&lead_acid, &sample(24.6, 0.0), at(30));
+        assert!((report.soc_percent - 64.5).abs() < 0.5);
+
+        let lifepo4 = Battery::new(100.0, Chemistry::Lifepo4, None, 24.0);
+        assert_eq!(lifepo4.cells, 8);
+        let mut estimator = Estimator::default();
+        estimator.update(&lifepo4, &sample(26.4, 0.0), at(0));
+        let report = estimator.update(&lifepo4, &sample(26.64, 0.0), at(40));
+        assert!((report.soc_percent - 50.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn corrects_the_capacity_for_the_temperature() {
+        let battery = Battery::new(100.0, Chemistry::LeadAcid, None, 24.0);
+        let at = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let capacity = |temp_grad0| {
+            let sample = MapInfo {
+                temp_grad0,
+                ..sample(24.2, 0.0)
+            };
+            Estimator::default()
+                .update(&battery, &sample, at)
+                .capacity_ah
+        };
+        assert!((capacity(25) - 100.0).abs() < 1e-9);
+        assert!((capacity(0) - 85.0).abs() < 1e-9);
+        // at most 10% above the rated capacity
+        assert!((capacity(45) - 110.0).abs() < 1e-9);
+        // -50 °C is a missing sensor, taken as 25 °C
+        assert!((capacity(-50) - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skips_gaps_between_samples() {
+        let battery = Battery::new(100.0, Chemistry::LeadAcid, None, 24.0);
+        let at = |minute| Local.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let mut estimator = Estimator::default();
+        estimator.update(&battery, &sample(24.2, -10.0), at(0));
+        // 20 minutes without samples are longer than MAX_GAP
+        let report = estimator.update(&battery, &sample(24.2, -10.0), at(20));
+        assert!((report.soc_percent - 50.0).abs() < 0.5);
+        let report = estimator.update(&battery, &sample(24.2, -10.0), at(26));
+        assert!((report.soc_percent - 49.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn keeps_the_estimate_across_restarts() {
+        let battery = Battery::new(100.0, Chemistry::LeadAcid, None, 24.0);
+        let at = |minute| Local.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let path = std::env::temp_dir().join(format!("map-soc-{}.json", std::process::id()));
+        let mut ledger = SocLedger::open(Some(&path)).unwrap();
+        ledger.update("1", &battery, &sample(24.2, -10.0), at(0));
+        let report = ledger.update("1", &battery, &sample(24.2, -10.0), at(6));
+        ledger.flush();
+
+        // the saved estimate wins over the voltage of the first sample
+        let mut ledger = SocLedger::open(Some(&path)).unwrap();
+        let restarted = ledger.update("1", &battery, &sample(25.0, -10.0), at(8));
+        assert!((restarted.soc_percent - report.soc_percent).abs() < 1e-9);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
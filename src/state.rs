@@ -0,0 +1,115 @@
+//! JSON files keeping totals and estimates across restarts.
+//!
+//! The bridge runs from the SD card of a Raspberry Pi, so a changed state is
+//! written at most every [`SAVE_INTERVAL`] and once more on shutdown. A file
+//! that cannot be written is logged and retried later, the bridge goes on with
+//! the state in memory.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    /// What the file keeps, for messages
+    what: &'static str,
+    saved: Option<Instant>,
+    pending: bool,
+}
+
+impl StateFile {
+    pub fn new(path: &Path, what: &'static str) -> Self {
+        Self {
+            path: path.into(),
+            what,
+            saved: None,
+            pending: false,
+        }
+    }
+
+    /// Reads the saved state, the default one when there is no file yet
+    pub fn load<T: DeserializeOwned + Default>(&self) -> anyhow::Result<T> {
+        if !self.path.exists() {
+            return Ok(T::default());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("cannot read {} {}", self.what, self.path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("cannot parse {} {}", self.what, self.path.display()))
+    }
+
+    /// The state changed, it is saved if the last save is [`SAVE_INTERVAL`] old
+    pub fn changed<T: Serialize>(&mut self, state: &T, now: Instant) {
+        self.pending = true;
+        if self
+            .saved
+            .is_none_or(|saved| now.saturating_duration_since(saved) >= SAVE_INTERVAL)
+        {
+            self.saved = Some(now);
+            self.save(state);
+        }
+    }
+
+    /// Saves a change not saved yet, on shutdown
+    pub fn flush<T: Serialize>(&mut self, state: &T) {
+        if self.pending {
+            self.save(state);
+        }
+    }
+
+    fn save<T: Serialize>(&mut self, state: &T) {
+        // written next to the file and renamed, a crash leaves the old state
+        let temporary = self.path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(state)
+            .map_err(Into::into)
+            .and_then(|json| fs::write(&temporary, json))
+            .and_then(|()| fs::rename(&temporary, &self.path));
+        match result {
+            Ok(()) => self.pending = false,
+            Err(error) => warn!(
+                "cannot save {} {}: {}",
+                self.what,
+                self.path.display(),
+                error
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_at_most_once_a_minute_and_on_flush() {
+        let path = std::env::temp_dir().join(format!("map-state-{}.json", std::process::id()));
+        let mut file = StateFile::new(&path, "test state");
+        assert_eq!(file.load::<Vec<u32>>().unwrap(), Vec::<u32>::new());
+
+        let t0 = Instant::now();
+        file.changed(&vec![1], t0);
+        file.changed(&vec![2], t0 + Duration::from_secs(30));
+        assert_eq!(file.load::<Vec<u32>>().unwrap(), [1]);
+        file.changed(&vec![3], t0 + SAVE_INTERVAL);
+        assert_eq!(file.load::<Vec<u32>>().unwrap(), [3]);
+        file.changed(&vec![4], t0 + SAVE_INTERVAL + Duration::from_secs(1));
+        file.flush(&vec![4]);
+        assert_eq!(file.load::<Vec<u32>>().unwrap(), [4]);
+        fs::remove_file(&path).unwrap();
+
+        // a missing directory is only logged
+        let path = std::env::temp_dir().join("map-state-missing/state.json");
+        let mut file = StateFile::new(&path, "test state");
+        file.changed(&vec![5], t0);
+        assert!(file.pending);
+    }
+}
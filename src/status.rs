@@ -0,0 +1,62 @@
+use paho_mqtt::{AsyncClient, Message, QOS_1};
+use serde::Serialize;
+
+use crate::watchdog::RecoveryStep;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeState {
+    Online,
+    Recovering,
+    Offline,
+}
+
+/// Payload of the retained `<topic>/status` message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BridgeStatus {
+    pub state: BridgeState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<RecoveryStep>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl BridgeStatus {
+    pub fn online() -> Self {
+        Self {
+            state: BridgeState::Online,
+            step: None,
+            message: None,
+        }
+    }
+
+    pub fn recovering(step: RecoveryStep, message: String) -> Self {
+        Self {
+            state: BridgeState::Recovering,
+            step: Some(step),
+            message: Some(message),
+        }
+    }
+
+    pub fn offline(message: String) -> Self {
+        Self {
+            state: BridgeState::Offline,
+            step: None,
+            message: Some(message),
+        }
+    }
+}
+
+pub fn status_topic(topic: &str) -> String {
+    format!("{topic}/status")
+}
+
+pub async fn publish(cli: &AsyncClient, topic: &str, status: &BridgeStatus) -> anyhow::Result<()> {
+    cli.publish(Message::new_retained(
+        topic,
+        serde_json::to_vec(status)?,
+        QOS_1,
+    ))
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::bail;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+use crate::map_protocol::high_level::MapInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One pretty printed document per poll, keyed by unit id with several MAPs
+    Json,
+    /// One compact object per MAP and poll
+    Jsonl,
+    /// A header line followed by one row per MAP and poll
+    Csv,
+    /// Aligned field names and values
+    Table,
+}
+
+/// Prints polled samples in the chosen format, every record carries a timestamp
+pub struct Printer {
+    format: Format,
+    /// Selected fields, for CSV the scalar fields of the first sample when none are given
+    fields: Vec<String>,
+    header_written: bool,
+}
+
+impl Printer {
+    /// `known_fields` are the names `fields` may use
+    pub fn new(
+        format: Format,
+        fields: Vec<String>,
+        known_fields: &[String],
+    ) -> anyhow::Result<Self> {
+        for field in &fields {
+            if !known_fields.contains(field) {
+                bail!("unknown field `{field}` in --fields");
+            }
+        }
+        Ok(Self {
+            format,
+            fields,
+            header_written: false,
+        })
+    }
+
+    /// Prints the samples of a single poll
+    pub fn print(
+        &mut self,
+        out: &mut impl Write,
+        timestamp: DateTime<Local>,
+        samples: &BTreeMap<String, MapInfo>,
+    ) -> anyhow::Result<()> {
+        let timestamp = timestamp.to_rfc3339();
+        let mut records = BTreeMap::new();
+        for (id, map_info) in samples {
+            records.insert(id.as_str(), self.select(map_info.fields()?));
+        }
+        match self.format {
+            Format::Json => {
+                let with_timestamp = |record: &Map<String, Value>| {
+                    let mut object = Map::new();
+                    object.insert("timestamp".into(), timestamp.clone().into());
+                    object.extend(record.clone());
+                    Value::Object(object)
+                };
+                // a single MAP is printed as before, several as an object keyed by unit id
+                let json = match records.len() {
+                    1 => with_timestamp(records.values().next().unwrap()),
+                    _ => records
+                        .iter()
+                        .map(|(id, record)| (id.to_string(), with_timestamp(record)))
+                        .collect::<Map<_, _>>()
+                        .into(),
+                };
+                writeln!(out, "{}", serde_json::to_string_pretty(&json)?)?;
+            }
+            Format::Jsonl => {
+                for (id, record) in records {
+                    let mut object = Map::new();
+                    object.insert("timestamp".into(), timestamp.clone().into());
+                    object.insert("unit".into(), id.into());
+                    object.extend(record);
+                    writeln!(out, "{}", Value::Object(object))?;
+                }
+            }
+            Format::Csv => {
+                if !self.header_written {
+                    if self.fields.is_empty() {
+                        self.fields = records
+                            .values()
+                            .next()
+                            .into_iter()
+                            .flatten()
+                            .filter(|(_, value)| !value.is_array() && !value.is_object())
+                            .map(|(field, _)| field.clone())
+                            .collect();
+                    }
+                    let header: Vec<&str> = ["timestamp", "unit"]
+                        .into_iter()
+                        .chain(self.fields.iter().map(String::as_str))
+                        .collect();
+                    writeln!(out, "{}", header.join(","))?;
+                    self.header_written = true;
+                }
+                for (id, record) in records {
+                    let mut row = vec![csv_escape(&timestamp), csv_escape(id)];
+                    row.extend(
+                        self.fields
+                            .iter()
+                            .map(|field| csv_escape(&plain(record.get(field)))),
+                    );
+                    writeln!(out, "{}", row.join(","))?;
+                }
+            }
+            Format::Table => {
+                for (id, record) in records {
+                    writeln!(out, "unit {id}  {timestamp}")?;
+                    let width = record.keys().map(String::len).max().unwrap_or_default();
+                    for (field, value) in &record {
+                        writeln!(out, "  {field:<width$}  {}", plain(Some(value)))?;
+                    }
+                }
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Selected fields of `sample`, all of them when none are selected
+    fn select(&self, mut sample: Map<String, Value>) -> Map<String, Value> {
+        if self.fields.is_empty() {
+            return sample;
+        }
+        let mut selected = Map::new();
+        for field in &self.fields {
+            if let Some(value) = sample.remove(field) {
+                selected.insert(field.clone(), value);
+            }
+        }
+        selected
+    }
+}
+
+/// Strings without quotes, nothing for missing values, other values as JSON
+fn plain(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(string)) => string.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn csv_has_a_header_and_selected_fields() {
+        let known = [
+            "u_acc".to_string(),
+            "p_load".to_string(),
+            "mode_label".to_string(),
+        ];
+        assert!(Printer::new(Format::Csv, vec!["nope".into()], &known).is_err());
+        let fields = vec!["u_acc".into(), "mode_label".into()];
+        let mut printer = Printer::new(Format::Csv, fields, &known).unwrap();
+        let mut samples = BTreeMap::new();
+        samples.insert(
+            "1".to_string(),
+            MapInfo {
+                u_acc: 45.3,
+                mode_label: "Off, no grid".into(),
+                ..Default::default()
+            },
+        );
+        let timestamp = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let mut out = Vec::new();
+        printer.print(&mut out, timestamp, &samples).unwrap();
+        printer.print(&mut out, timestamp, &samples).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "timestamp,unit,u_acc,mode_label");
+        assert!(lines[1].ends_with(",1,45.3,\"Off, no grid\""));
+        assert_eq!(lines[1], lines[2]);
+    }
+
+    #[test]
+    fn jsonl_records_carry_timestamp_and_unit() {
+        let known = ["p_load".to_string(), "u_acc".to_string()];
+        let fields = vec!["p_load".into(), "u_acc".into()];
+        let mut printer = Printer::new(Format::Jsonl, fields, &known).unwrap();
+        let mut samples = BTreeMap::new();
+        samples.insert("1".to_string(), MapInfo::default());
+        let sample = MapInfo {
+            u_acc: 45.3,
+            ..Default::default()
+        };
+        samples.insert("2".to_string(), sample);
+        let mut out = Vec::new();
+        printer.print(&mut out, Local::now(), &samples).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let records: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["unit"], "2");
+        assert_eq!(records[1]["p_load"], 0);
+        assert!(records[1]["timestamp"].is_string());
+        assert!(records[1].get("mode").is_none());
+        assert!(text.contains(r#""u_acc":45.3,"#), "{text}");
+    }
+}
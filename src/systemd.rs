@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use sd_notify::NotifyState;
+
+/// Sends a notification to systemd. Does nothing when not started by systemd
+/// with `Type=notify`, errors are only logged.
+fn notify(state: &[NotifyState]) {
+    if let Err(error) = sd_notify::notify(false, state) {
+        warn!("cannot notify systemd: {}", error);
+    }
+}
+
+/// The MAP is identified and the broker is connected
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+/// Keeps the systemd watchdog happy, see [`report`]
+fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Free-form status shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+#[derive(Debug)]
+struct UnitHealth {
+    last_success: Option<Instant>,
+    interval: Duration,
+    status: String,
+}
+
+/// Polls of every unit, so the watchdog is kept only while all of them answer
+/// and the status shows each of them
+#[derive(Debug)]
+pub struct Health {
+    units: BTreeMap<String, UnitHealth>,
+}
+
+impl Health {
+    pub fn new(ids: impl IntoIterator<Item = String>) -> Self {
+        let units = ids
+            .into_iter()
+            .map(|id| {
+                let unit = UnitHealth {
+                    last_success: None,
+                    interval: Duration::ZERO,
+                    status: "starting".into(),
+                };
+                (id, unit)
+            })
+            .collect();
+        Self { units }
+    }
+
+    /// A successful poll of unit `id`, which polls every `interval`
+    pub fn succeeded(&mut self, id: &str, interval: Duration, now: Instant) {
+        if let Some(unit) = self.units.get_mut(id) {
+            unit.last_success = Some(now);
+            unit.interval = interval;
+        }
+    }
+
+    pub fn set_status(&mut self, id: &str, status: String) {
+        if let Some(unit) = self.units.get_mut(id) {
+            unit.status = status;
+        }
+    }
+
+    /// Every unit polled successfully within its last two polling intervals,
+    /// the time a poll takes is not counted in the interval
+    pub fn alive(&self, now: Instant) -> bool {
+        self.units.values().all(|unit| {
+            unit.last_success
+                .is_some_and(|at| now.saturating_duration_since(at) <= 2 * unit.interval)
+        })
+    }
+
+    pub fn status(&self) -> String {
+        let units: Vec<String> = self
+            .units
+            .iter()
+            .map(|(id, unit)| format!("unit {id}: {}", unit.status))
+            .collect();
+        units.join("; ")
+    }
+}
+
+/// Keeps the watchdog happy while every unit answers and shows the status of all of them
+pub fn report(health: &Health, now: Instant) {
+    if health.alive(now) {
+        watchdog();
+    }
+    status(&health.status());
+}
+
+/// Example unit running the bridge in MQTT mode with settings taken from
+/// environment variables in `env_file`
+pub fn unit_file(exe: &str, env_file: &str, user: Option<&str>, watchdog: Duration) -> String {
+    let user = user
+        .map(|user| format!("User={user}\n"))
+        .unwrap_or_default();
+    format!(
+        "[Unit]
+Description=MAP invertor to MQTT bridge
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={exe} mqtt
+ExecReload=/bin/kill -HUP $MAINPID
+# MAP_PORT, MAP_PORT_SPEED, MQTT_HOSTNAME, MQTT_PORT, MQTT_USERNAME, MQTT_PASSWORD, ...
+EnvironmentFile={env_file}
+{user}WatchdogSec={watchdog}
+Restart=on-failure
+RestartSec=10
+
+[Install]
+WantedBy=multi-user.target
+",
+        watchdog = watchdog.as_secs()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_needs_every_unit() {
+        let t0 = Instant::now();
+        let secs = Duration::from_secs;
+        let mut health = Health::new(["1".to_string(), "2".to_string()]);
+        health.succeeded("1", secs(10), t0);
+        assert!(!health.alive(t0), "unit 2 never answered");
+        health.succeeded("2", secs(10), t0 + secs(5));
+        assert!(health.alive(t0 + secs(20)));
+        health.succeeded("2", secs(10), t0 + secs(25));
+        assert!(!health.alive(t0 + secs(21)), "unit 1 stopped answering");
+
+        health.set_status("1", "cannot read map info".into());
+        health.set_status("2", "mode: 3".into());
+        assert_eq!(
+            health.status(),
+            "unit 1: cannot read map info; unit 2: mode: 3"
+        );
+    }
+
+    #[test]
+    fn unit_file_runs_the_bridge_with_a_watchdog() {
+        let unit = unit_file(
+            "/usr/local/bin/map-invertor-mqtt-bridge",
+            "/etc/default/map",
+            Some("pi"),
+            Duration::from_secs(60),
+        );
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("ExecStart=/usr/local/bin/map-invertor-mqtt-bridge mqtt\n"));
+        assert!(unit.contains("EnvironmentFile=/etc/default/map\nUser=pi\nWatchdogSec=60\n"));
+
+        let unit = unit_file("map", "/etc/default/map", None, Duration::from_secs(30));
+        assert!(!unit.contains("User="));
+        assert!(unit.contains("EnvironmentFile=/etc/default/map\nWatchdogSec=30\n"));
+    }
+}
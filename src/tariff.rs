@@ -0,0 +1,242 @@
+//! Money spent on and saved from the grid under a time-of-day tariff.
+//!
+//! Every sample stands for the time since the previous one, at most [`MAX_GAP`],
+//! at the prices of its own time:
+//!
+//! * `cost` - `p_net` at the import price, minus `p_net` at the export price
+//!   while the MAP sells to the grid
+//! * `savings` - `p_load` at the import price minus `cost`, what the battery and
+//!   the tariff modes saved compared to taking the load from the grid
+//! * `battery` - battery discharge minus charge at the import price, the part of
+//!   the savings the battery shifted between tariff periods
+//!
+//! Totals start from zero at local midnight. With `--energy-state` they are
+//! saved next to the energy totals and survive restarts.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::map_protocol::{high_level::MapInfo, mode::MapModeExtended};
+
+/// Longer pauses between samples are not filled in, the bridge was probably not running
+const MAX_GAP: Duration = Duration::from_secs(600);
+
+/// `[tariff]` of the config file
+///
+/// ```toml
+/// [tariff]
+/// currency = "RUB"
+///
+/// [[tariff.period]]
+/// from = "07:00"
+/// to = "23:00"
+/// import = 6.5
+///
+/// [[tariff.period]]
+/// from = "23:00"
+/// to = "07:00"
+/// import = 3.2
+/// export = 2.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tariff {
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(rename = "period")]
+    periods: Vec<Period>,
+}
+
+/// Prices per kWh from `from` until `to`, across midnight when `to` is earlier
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Period {
+    #[serde(deserialize_with = "deserialize_time")]
+    from: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    to: NaiveTime,
+    import: f64,
+    #[serde(default)]
+    export: f64,
+}
+
+impl Period {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&time) || self.from == self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+impl Tariff {
+    /// Every minute of the day must have a price
+    pub fn check(&self) -> anyhow::Result<()> {
+        for minute in 0..24 * 60 {
+            let time = NaiveTime::from_hms_opt(minute / 60, minute % 60, 0).unwrap();
+            if self.prices(time).is_none() {
+                bail!("no tariff period covers {}", time.format("%H:%M"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Import and export price, of the first period containing `time`
+    pub fn prices(&self, time: NaiveTime) -> Option<(f64, f64)> {
+        self.periods
+            .iter()
+            .find(|period| period.contains(time))
+            .map(|period| (period.import, period.export))
+    }
+}
+
+/// Payload of the retained `<topic>/cost` message
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyCost {
+    pub date: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub import_price: f64,
+    pub export_price: f64,
+    pub cost: f64,
+    pub savings: f64,
+    pub battery: f64,
+}
+
+/// Running cost of one MAP
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CostMeter {
+    last_sample: Option<DateTime<Local>>,
+    daily: DailyCost,
+}
+
+impl CostMeter {
+    pub fn update(
+        &mut self,
+        tariff: &Tariff,
+        sample: &MapInfo,
+        now: DateTime<Local>,
+    ) -> &DailyCost {
+        let today = now.date_naive();
+        if self.daily.date != Some(today) {
+            self.daily = DailyCost {
+                date: Some(today),
+                ..Default::default()
+            };
+        }
+        let hours = self
+            .last_sample
+            .and_then(|last| (now - last).to_std().ok())
+            .filter(|elapsed| *elapsed <= MAX_GAP)
+            .map(|elapsed| elapsed.as_secs_f64() / 3600.0)
+            .unwrap_or_default();
+        self.last_sample = Some(now);
+        let (import_price, export_price) = tariff.prices(now.time()).unwrap_or_default();
+
+        let kwh = |watts: f64| watts * hours / 1000.0;
+        let grid = kwh(f64::from(sample.p_net));
+        let cost = if selling(&sample.mode) {
+            -grid * export_price
+        } else {
+            grid * import_price
+        };
+        let battery = -kwh(f64::from(sample.u_acc * sample.battery_current)) * import_price;
+        let daily = &mut self.daily;
+        daily.currency = tariff.currency.clone();
+        daily.import_price = import_price;
+        daily.export_price = export_price;
+        daily.cost += cost;
+        daily.savings += kwh(f64::from(sample.p_load)) * import_price - cost;
+        daily.battery += battery;
+        &self.daily
+    }
+
+    pub fn daily(&self) -> &DailyCost {
+        &self.daily
+    }
+}
+
+/// Modes in which `p_net` flows to the grid
+fn selling(mode: &MapModeExtended) -> bool {
+    matches!(
+        mode,
+        MapModeExtended::TranslationSellingBackToGrid
+            | MapModeExtended::SellingBackToGridTranslation
+    )
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn tariff() -> Tariff {
+        toml::from_str(
+            r#"
+            [[period]]
+            from = "07:00"
+            to = "23:00"
+            import = 6.0
+
+            [[period]]
+            from = "23:00"
+            to = "07:00"
+            import = 3.0
+            export = 2.0
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_prices_across_midnight() {
+        let tariff = tariff();
+        tariff.check().unwrap();
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(tariff.prices(at(12, 0)), Some((6.0, 0.0)));
+        assert_eq!(tariff.prices(at(23, 0)), Some((3.0, 2.0)));
+        assert_eq!(tariff.prices(at(6, 59)), Some((3.0, 2.0)));
+        let mut gap = tariff.clone();
+        gap.periods.pop();
+        assert!(gap.check().is_err());
+    }
+
+    #[test]
+    fn accumulates_cost_and_savings() {
+        let tariff = tariff();
+        let mut meter = CostMeter::default();
+        let at = |h| Local.with_ymd_and_hms(2024, 5, 1, h, 0, 0).unwrap();
+        // 1 kW of load, half of it from the grid, for ten minutes at the day price
+        let sample = MapInfo {
+            p_load: 1000,
+            p_net: 500,
+            ..Default::default()
+        };
+        meter.update(&tariff, &sample, at(10));
+        let daily = meter.update(&tariff, &sample, at(10) + chrono::Duration::minutes(10));
+        assert!((daily.cost - 0.5).abs() < 1e-9);
+        assert!((daily.savings - 0.5).abs() < 1e-9);
+
+        let selling = MapInfo {
+            mode: MapModeExtended::TranslationSellingBackToGrid,
+            p_net: 600,
+            ..Default::default()
+        };
+        let mut meter = CostMeter::default();
+        meter.update(&tariff, &selling, at(1));
+        let daily = meter.update(&tariff, &selling, at(1) + chrono::Duration::minutes(10));
+        assert!((daily.cost + 0.2).abs() < 1e-9);
+    }
+}
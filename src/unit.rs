@@ -0,0 +1,332 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use chrono::Local;
+use log::{info, trace, warn};
+use paho_mqtt::{AsyncClient, Message, QOS_1};
+use tokio::sync::mpsc;
+
+use crate::{
+    envelope::{Envelope, MapIdentity},
+    home_assistant,
+    legacy_shm::LegacyShm,
+    map_actor::{self, MapHandle},
+    map_protocol::{high_level::MapInfo, registers::RegisterMap},
+    signals::{Signal, SignalReceiver},
+    status::{self, BridgeStatus},
+    systemd::{self, Health},
+    watchdog::{RecoveryStep, StaleWatchdog},
+    PublishArgs,
+};
+
+/// `[ID=]PATH` as given on the command line, e.g. `/dev/ttyUSB0` or `2=/dev/ttyUSB1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitSpec {
+    pub id: Option<String>,
+    pub path: String,
+}
+
+impl FromStr for UnitSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, path) = match s.split_once('=') {
+            Some((id, path)) => (Some(id.trim()), path.trim()),
+            None => (None, s.trim()),
+        };
+        if path.is_empty() {
+            bail!("port path is empty in `{s}`");
+        }
+        if let Some(id) = id {
+            if id.is_empty() || id.contains(['/', '+', '#']) {
+                bail!("unit id must be non-empty and usable as an MQTT topic level in `{s}`");
+            }
+        }
+        Ok(Self {
+            id: id.map(Into::into),
+            path: path.into(),
+        })
+    }
+}
+
+/// Unit ids and port paths, ids default to the position on the command line starting from 1
+pub fn resolve(specs: &[UnitSpec]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut units: Vec<(String, String)> = Vec::with_capacity(specs.len());
+    for (index, spec) in specs.iter().enumerate() {
+        let id = spec.id.clone().unwrap_or((index + 1).to_string());
+        if units.iter().any(|(other, _)| *other == id) {
+            return Err(anyhow!("unit id `{id}` is used twice"));
+        }
+        units.push((id, spec.path.clone()));
+    }
+    Ok(units)
+}
+
+/// Settings and channels shared by all polling tasks
+pub struct UnitContext {
+    pub cli: AsyncClient,
+    /// Asks the owner of the client to reconnect to the broker, the client is
+    /// shared with the other units
+    pub reconnect: mpsc::Sender<()>,
+    pub publish: PublishArgs,
+    pub stale_timeout: Duration,
+    pub recovery_step_timeout: Duration,
+    pub recovery_rounds: u32,
+    /// Every sample read, for the aggregated topic
+    pub samples: mpsc::Sender<(String, MapInfo)>,
+    pub registers: Arc<RegisterMap>,
+    /// Home Assistant discovery prefix, discovery configs are published when set
+    pub discovery_prefix: Option<String>,
+    pub health: Arc<Mutex<Health>>,
+}
+
+/// Publish errors are only logged, the broker may be reconnecting for another unit
+pub async fn try_publish(cli: &AsyncClient, msg: Message) -> bool {
+    match cli.publish(msg).await {
+        Ok(()) => true,
+        Err(error) => {
+            warn!("cannot publish to MQTT broker: {}", error);
+            false
+        }
+    }
+}
+
+async fn publish_status(cli: &AsyncClient, topic: &str, status: &BridgeStatus) {
+    if let Err(error) = status::publish(cli, topic, status).await {
+        warn!("cannot publish status: {}", error);
+    }
+}
+
+/// One MAP with its own port, polling task and MQTT topic
+pub struct Unit {
+    pub id: String,
+    /// Serial port path, published with every sample
+    pub port: String,
+    pub topic: String,
+    pub map: MapHandle,
+    pub legacy_shm: Option<LegacyShm>,
+}
+
+impl Unit {
+    pub fn status_topic(&self) -> String {
+        status::status_topic(&self.topic)
+    }
+
+    /// Polls the MAP until a shutdown signal arrives. Errors are also reported
+    /// as the offline status of the unit.
+    pub async fn run(
+        mut self,
+        context: UnitContext,
+        signals: SignalReceiver,
+    ) -> anyhow::Result<()> {
+        let status_topic = self.status_topic();
+        let cli = context.cli.clone();
+        let status = match self.poll(context, signals).await {
+            Ok(stopped_by) => BridgeStatus::offline(format!("stopped by {stopped_by}")),
+            Err(error) => {
+                let status = BridgeStatus::offline(format!("{error:#}"));
+                publish_status(&cli, &status_topic, &status).await;
+                return Err(error.context(format!("unit {}", self.id)));
+            }
+        };
+        publish_status(&cli, &status_topic, &status).await;
+        Ok(())
+    }
+
+    async fn poll(
+        &mut self,
+        context: UnitContext,
+        mut signals: SignalReceiver,
+    ) -> anyhow::Result<&'static str> {
+        let UnitContext {
+            cli,
+            reconnect,
+            publish,
+            stale_timeout,
+            recovery_step_timeout,
+            recovery_rounds,
+            samples,
+            registers,
+            discovery_prefix,
+            health,
+        } = context;
+        let report = |status: String, success: Option<(Duration, Instant)>| {
+            let mut health = health.lock().unwrap();
+            if let Some((interval, now)) = success {
+                health.succeeded(&self.id, interval, now);
+            }
+            health.set_status(&self.id, status);
+            systemd::report(&health, Instant::now());
+        };
+        let (mut interval, mut publish_policy) = publish.load(&registers)?;
+        let status_topic = self.status_topic();
+        let metrics_topic = format!("{}/metrics", self.topic);
+
+        publish_status(&cli, &status_topic, &BridgeStatus::online()).await;
+        if let Some(prefix) = &discovery_prefix {
+            for msg in
+                home_assistant::discovery(&registers, prefix, &self.id, &self.topic, &status_topic)
+            {
+                try_publish(&cli, msg).await;
+            }
+        }
+
+        let mut last_error: Option<(String, Instant)> = None;
+        let mut watchdog = StaleWatchdog::new(
+            stale_timeout,
+            recovery_step_timeout,
+            recovery_rounds,
+            Instant::now(),
+        );
+        let mut prev_map_info = MapInfo::default();
+        let mut seq = 0u64;
+        loop {
+            let now = Instant::now();
+            let started = Local::now();
+            seq += 1;
+            let changed = match self.map.read_status().await {
+                Ok(map_info) => {
+                    if let Some(reason) = publish_policy.check(&map_info, now)? {
+                        trace!("unit {}: publishing map info: {}", self.id, reason);
+                        let identity = MapIdentity {
+                            unit: &self.id,
+                            port: &self.port,
+                        };
+                        let envelope =
+                            Envelope::new(&map_info, identity, seq, started, now.elapsed());
+                        let msg = Message::new_retained(
+                            &self.topic,
+                            serde_json::to_vec(&envelope)?,
+                            QOS_1,
+                        );
+                        if try_publish(&cli, msg).await {
+                            publish_policy.published(&map_info, now)?;
+                        }
+                    }
+                    trace!("unit {}: map info: {:?}", self.id, &map_info);
+                    if let Some(legacy_shm) = &mut self.legacy_shm {
+                        legacy_shm.update(&map_info);
+                    }
+                    let last_error = match &last_error {
+                        Some((error, at)) => format!("{error}, {} s ago", at.elapsed().as_secs()),
+                        None => "none".into(),
+                    };
+                    report(
+                        format!("mode: {:?}, last error: {last_error}", map_info.mode()),
+                        Some((interval, now)),
+                    );
+                    let changed = prev_map_info != map_info;
+                    if samples
+                        .send((self.id.clone(), map_info.clone()))
+                        .await
+                        .is_err()
+                    {
+                        bail!("sample receiver stopped");
+                    }
+                    prev_map_info = map_info;
+                    changed
+                }
+                Err(error) if map_actor::is_transient(&error) => {
+                    warn!("unit {}: cannot read map info: {}", self.id, error);
+                    last_error = Some((error.to_string(), now));
+                    report(format!("cannot read map info: {error}"), None);
+                    false
+                }
+                Err(error) => return Err(error),
+            };
+            let stats = self.map.stats().await?;
+            trace!("unit {}: protocol stats: {:?}", self.id, stats);
+            try_publish(
+                &cli,
+                Message::new(&metrics_topic, serde_json::to_vec(&stats)?, QOS_1),
+            )
+            .await;
+            trace!(
+                "unit {}: map info unchanged for: {:?}",
+                self.id,
+                watchdog.stale_for(now)
+            );
+            if changed {
+                if watchdog.data_changed(now) {
+                    info!("unit {}: map info is changing again, recovered", self.id);
+                    publish_status(&cli, &status_topic, &BridgeStatus::online()).await;
+                }
+            } else if let Some(step) = watchdog.poll(now) {
+                let message = format!(
+                    "map info not changed for {} seconds",
+                    watchdog.stale_for(now).as_secs()
+                );
+                warn!("unit {}: {}, recovery step: {}", self.id, message, step);
+                report(format!("{message}, recovery step: {step}"), None);
+                let status = if step == RecoveryStep::Exit {
+                    BridgeStatus::offline(message.clone())
+                } else {
+                    BridgeStatus::recovering(step, message.clone())
+                };
+                publish_status(&cli, &status_topic, &status).await;
+                match step {
+                    RecoveryStep::ReopenPort => {
+                        if let Err(error) = self.map.reopen().await {
+                            warn!("cannot reopen map port: {:#}", error);
+                        }
+                    }
+                    RecoveryStep::Reidentify => {
+                        if let Err(error) = self.map.identify().await {
+                            warn!("cannot identify MAP: {:#}", error);
+                        }
+                    }
+                    // a full queue means a reconnection is already pending
+                    RecoveryStep::ReconnectMqtt => {
+                        let _ = reconnect.try_send(());
+                    }
+                    RecoveryStep::Exit => bail!("{}, recovery failed, exiting", message),
+                }
+            }
+
+            let signal = tokio::select! {
+                _ = tokio::time::sleep(interval) => continue,
+                signal = signals.recv() => signal,
+            };
+            match signal {
+                Some(Signal::Shutdown(name)) => return Ok(name),
+                Some(Signal::Reload) => match publish.load(&registers) {
+                    Ok((new_interval, new_publish_policy)) => {
+                        info!("unit {}: configuration reloaded", self.id);
+                        interval = new_interval;
+                        publish_policy = new_publish_policy;
+                    }
+                    Err(error) => warn!("cannot reload configuration: {:#}", error),
+                },
+                None => bail!("signal handler stopped"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_unit_ids() {
+        let specs: Vec<UnitSpec> = ["/dev/ttyUSB0", "b=/dev/ttyUSB1", "/dev/ttyUSB2"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let units = resolve(&specs).unwrap();
+        assert_eq!(units[0], ("1".into(), "/dev/ttyUSB0".into()));
+        assert_eq!(units[1], ("b".into(), "/dev/ttyUSB1".into()));
+        assert_eq!(units[2], ("3".into(), "/dev/ttyUSB2".into()));
+
+        assert!("a/b=/dev/ttyUSB0".parse::<UnitSpec>().is_err());
+        let twice = [
+            "2=/dev/ttyUSB0".parse().unwrap(),
+            "/dev/ttyUSB1".parse().unwrap(),
+        ];
+        assert!(resolve(&twice).is_err());
+    }
+}
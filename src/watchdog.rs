@@ -0,0 +1,133 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Recovery actions taken, in this order, while the MAP keeps returning identical data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryStep {
+    /// Flush serial buffers, close and open the port again
+    ReopenPort,
+    /// Read EEPROM again and check that a MAP answers
+    Reidentify,
+    /// Drop and re-establish the MQTT connection
+    ReconnectMqtt,
+    /// Nothing helped, give up and let the supervisor restart us
+    Exit,
+}
+
+impl Display for RecoveryStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecoveryStep::ReopenPort => write!(f, "reopen serial port"),
+            RecoveryStep::Reidentify => write!(f, "re-identify MAP"),
+            RecoveryStep::ReconnectMqtt => write!(f, "reconnect MQTT"),
+            RecoveryStep::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+const LADDER: [RecoveryStep; 3] = [
+    RecoveryStep::ReopenPort,
+    RecoveryStep::Reidentify,
+    RecoveryStep::ReconnectMqtt,
+];
+
+/// Tracks how long the polled data has stayed unchanged and escalates through
+/// [`RecoveryStep`]s.
+///
+/// The first step fires after `stale_timeout` without changes, every following
+/// one after another `step_timeout`. The ladder is walked `rounds` times before
+/// [`RecoveryStep::Exit`] is returned.
+#[derive(Debug)]
+pub struct StaleWatchdog {
+    stale_timeout: Duration,
+    step_timeout: Duration,
+    rounds: u32,
+    last_change: Instant,
+    last_step: Option<Instant>,
+    steps_taken: usize,
+}
+
+impl StaleWatchdog {
+    pub fn new(stale_timeout: Duration, step_timeout: Duration, rounds: u32, now: Instant) -> Self {
+        Self {
+            stale_timeout,
+            step_timeout,
+            rounds,
+            last_change: now,
+            last_step: None,
+            steps_taken: 0,
+        }
+    }
+
+    /// Resets the ladder. Returns `true` if a recovery was in progress.
+    pub fn data_changed(&mut self, now: Instant) -> bool {
+        let was_recovering = self.steps_taken > 0;
+        self.last_change = now;
+        self.last_step = None;
+        self.steps_taken = 0;
+        was_recovering
+    }
+
+    /// How long the data has been unchanged
+    pub fn stale_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_change)
+    }
+
+    /// Returns the next step to take if it is due
+    pub fn poll(&mut self, now: Instant) -> Option<RecoveryStep> {
+        let due = match self.last_step {
+            None => self.stale_for(now) >= self.stale_timeout,
+            Some(last_step) => now.saturating_duration_since(last_step) >= self.step_timeout,
+        };
+        if !due {
+            return None;
+        }
+        let step = if self.steps_taken >= LADDER.len() * self.rounds as usize {
+            RecoveryStep::Exit
+        } else {
+            LADDER[self.steps_taken % LADDER.len()]
+        };
+        self.steps_taken += 1;
+        self.last_step = Some(now);
+        Some(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalates_and_resets() {
+        let t0 = Instant::now();
+        let secs = Duration::from_secs;
+        let mut watchdog = StaleWatchdog::new(secs(300), secs(60), 1, t0);
+
+        assert_eq!(watchdog.poll(t0 + secs(299)), None);
+        assert_eq!(
+            watchdog.poll(t0 + secs(300)),
+            Some(RecoveryStep::ReopenPort)
+        );
+        assert_eq!(watchdog.poll(t0 + secs(330)), None);
+        assert_eq!(
+            watchdog.poll(t0 + secs(360)),
+            Some(RecoveryStep::Reidentify)
+        );
+        assert!(watchdog.data_changed(t0 + secs(370)));
+        assert_eq!(watchdog.poll(t0 + secs(430)), None);
+
+        let t1 = t0 + secs(670);
+        assert_eq!(watchdog.poll(t1), Some(RecoveryStep::ReopenPort));
+        assert_eq!(watchdog.poll(t1 + secs(60)), Some(RecoveryStep::Reidentify));
+        assert_eq!(
+            watchdog.poll(t1 + secs(120)),
+            Some(RecoveryStep::ReconnectMqtt)
+        );
+        assert_eq!(watchdog.poll(t1 + secs(180)), Some(RecoveryStep::Exit));
+    }
+}